@@ -1,17 +1,81 @@
-use anyhow::Result;
-// use regex::Regex;
+use anyhow::{Context, Result};
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag, TagEnd};
+use regex::Regex;
 // use std::borrow::Cow;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::process::Command;
+use std::collections::HashMap;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-use crate::conf_api::Page;
-use crate::Config;
-use crate::Api;
+use concmd::conf_api::{
+    self, ApiError, BodyFormat, Content, ContentProperty, ContentType, OperationRestrictions, Page,
+    PageRestrictions, PageSummary, Restriction, Space, Task, User, WatchTarget,
+};
+use concmd::frontmatter::{self, Frontmatter, PageRef};
+use concmd::{adf, convert, crypt, lint, Api, AutoSync, Config, LegacyEditor, SaveMode};
+
+use crate::audit::{self, Operation as AuditOperation};
+use crate::checkpoint::Checkpoint;
+use crate::mentions;
+use crate::plan::{self, Operation};
+use crate::serve;
+use crate::state::{self, PendingEdit, State};
+use crate::{CopyTarget, Format, OnConflict, PreviewLength, SpaceSort};
+
+// Exit codes for commands meant to be scripted
+const EXIT_AUTH_FAILED: i32 = 2;
+const EXIT_REQUEST_FAILED: i32 = 3;
+const EXIT_CONFIG_INVALID: i32 = 4;
+// As a shell reports a command killed by Ctrl-C
+const EXIT_USER_CANCEL: i32 = 130;
+
+// Set by --quiet for scripts, which only want a command's data on stdout
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Progress and success messages, left out with --quiet. Prompts, data and
+// anything on stderr are always printed.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
 
 // Interface
 
+// Ctrl-C cancels whatever concmd is doing, whether waiting at a prompt or part
+// way through an upload. Edits that haven't been published are left where they
+// were saved and offered again on the next run, so say where they are first.
+pub fn handle_interrupts() {
+    ctrlc::set_handler(|| {
+        println!();
+        close_decrypted_copies();
+        let pending = State::load()
+            .map(|state| state.pending_edits)
+            .unwrap_or_default();
+        for edit in pending {
+            eprintln!(
+                "Cancelled, your edit to {} is saved at {}",
+                edit.title,
+                edit.file_path.display()
+            );
+        }
+        std::process::exit(EXIT_USER_CANCEL);
+    })
+    .expect("failed to set the Ctrl-C handler");
+}
+
 pub fn fetch_page(_space: &String, _page: &String, _filename: &PathBuf) {
     todo!()
 }
@@ -20,34 +84,2739 @@ pub fn publish_page(_space: &String, _page: &String, _filename: &PathBuf) {
     todo!()
 }
 
+// Pages made in the new editor come back as ADF, anything else was made in the
+// legacy editor
+fn editor_name(page: &Page) -> &str {
+    match page.body_format() {
+        BodyFormat::Adf => "new",
+        BodyFormat::Storage => "legacy",
+    }
+}
+
+fn check_legacy_editor(config: &Config, page: &Page) -> Result<()> {
+    if page.body_format() == BodyFormat::Adf {
+        return Ok(());
+    }
+    match config.legacy_editor {
+        LegacyEditor::Warn => {
+            eprintln!(
+                "Warning: {} was made in the legacy editor, some formatting may be lost",
+                page.title
+            );
+            Ok(())
+        }
+        LegacyEditor::Refuse => anyhow::bail!(
+            "{} was made in the legacy editor and may lose formatting, pass --force to edit it anyway",
+            page.title
+        ),
+    }
+}
+
+// How a command finished, turned into an exit code by exit_with. Commands
+// return this rather than exiting themselves, so the exit codes are decided in
+// one place.
+pub enum CommandOutcome {
+    Done,
+    // The user backed out, or the editor exited with an error
+    Cancelled,
+    // The command couldn't do what was asked, with the reason to print
+    Failed(String),
+}
+
+// Exits with the code for a command's outcome, or returns if it succeeded,
+// printing the reason it failed first
+pub fn exit_with(outcome: Result<CommandOutcome>) {
+    match &outcome {
+        Ok(CommandOutcome::Failed(reason)) => eprintln!("{}", reason),
+        Err(e) => eprintln!("Error: {:#}", e),
+        _ => (),
+    }
+    if let Some(code) = exit_code(&outcome) {
+        std::process::exit(code);
+    }
+}
+
+// The exit code for a command's outcome, or None if it succeeded. Errors from
+// Confluence get the same codes as check-auth.
+fn exit_code(outcome: &Result<CommandOutcome>) -> Option<i32> {
+    match outcome {
+        Ok(CommandOutcome::Done) => None,
+        Ok(CommandOutcome::Cancelled) => Some(EXIT_USER_CANCEL),
+        Ok(CommandOutcome::Failed(_)) => Some(1),
+        Err(e) => match e.downcast_ref::<ApiError>() {
+            Some(ApiError::Unauthorized { .. }) => Some(EXIT_AUTH_FAILED),
+            Some(_) => Some(EXIT_REQUEST_FAILED),
+            None => Some(1),
+        },
+    }
+}
+
 // full workflow for page edit: pulls page, opens nvim, pushes page
-pub fn edit_page_by_id(config: &Config, id: &String) {
-    let mut page = Page::get_page_by_id(&config.api, id).unwrap();
-    let file_path = save_page_to_file(&config.save_location, id, page.get_body()).unwrap(); // figure out errors here
-    open_editor(&file_path);
-    print!("Do you wish to publish this page: y/n?  ");
+pub fn edit_page_by_id(config: &Config, id: &str) -> Result<CommandOutcome> {
+    let page = Page::get_page_by_id(&config.api, id)?;
+    check_legacy_editor(config, &page)?;
+    let file_path = save_page_to_file(config, &page)?;
+    state::add_pending_edit(PendingEdit {
+        id: page.id.clone(),
+        title: page.title.clone(),
+        file_path: file_path.clone(),
+    })?;
+    let saved = crypt::read_to_string(&file_path)?;
+    match edit_saved_page(config, page, &file_path, Some(&saved))? {
+        PublishOutcome::Cancelled => Ok(CommandOutcome::Cancelled),
+        _ => Ok(CommandOutcome::Done),
+    }
+}
+
+pub fn edit_page_by_path(config: &Config, path: &str) -> Result<CommandOutcome> {
+    match resolve_page_path(&config.api, path)? {
+        Some(id) => edit_page_by_id(config, &id),
+        None => Ok(CommandOutcome::Failed(format!("No page at {}", path))),
+    }
+}
+
+pub fn edit_space_homepage(config: &Config, key: &str) -> Result<CommandOutcome> {
+    let space = Space::get_space_by_key_or_id(&config.api, key)?;
+    match space.homepage_id {
+        Some(id) => edit_page_by_id(config, &id),
+        None => Ok(CommandOutcome::Failed(format!(
+            "Space {} has no homepage",
+            space.key
+        ))),
+    }
+}
+
+// Downloads several pages and opens them in one editor session, then publishes
+// each in turn and reports what happened to all of them
+pub fn edit_pages_by_id(config: &Config, ids: &[String]) -> Result<CommandOutcome> {
+    let mut edits = Vec::new();
+    for id in ids {
+        let page = Page::get_page_by_id(&config.api, id)?;
+        check_legacy_editor(config, &page)?;
+        let file_path = save_page_to_file(config, &page)?;
+        state::add_pending_edit(PendingEdit {
+            id: page.id.clone(),
+            title: page.title.clone(),
+            file_path: file_path.clone(),
+        })?;
+        let saved = crypt::read_to_string(&file_path)?;
+        edits.push((page, file_path, saved));
+    }
+    let paths: Vec<&Path> = edits.iter().map(|(_, path, _)| path.as_path()).collect();
+    let edited = open_editor(config, &paths)?;
+
+    let mut summary = Vec::new();
+    let mut failed = 0;
+    for (page, file_path, saved) in edits {
+        let title = page.title.clone();
+        if !edited {
+            state::remove_pending_edit(&page.id)?;
+            summary.push((title, PublishOutcome::Cancelled.to_string()));
+            continue;
+        }
+        let outcome = match publish_edited_page(config, page, &file_path, Some(&saved)) {
+            Ok(outcome) => outcome.to_string(),
+            // Left as a pending edit, so it is offered again on the next run
+            Err(e) => {
+                failed += 1;
+                format!("failed: {}", e)
+            }
+        };
+        summary.push((title, outcome));
+    }
+    info!();
+    for (title, outcome) in &summary {
+        info!("{}: {}", title, outcome);
+    }
+    Ok(match (edited, failed) {
+        (false, _) => CommandOutcome::Cancelled,
+        (true, 0) => CommandOutcome::Done,
+        (true, failed) => CommandOutcome::Failed(format!(
+            "{} of {} page(s) failed to publish",
+            failed,
+            summary.len()
+        )),
+    })
+}
+
+// Offers to publish, discard or reopen any edits left over from a previous run
+// that never made it to Confluence
+pub fn recover_unsynced_edits(config: &Config) {
+    let state = State::load().unwrap();
+    if state.pending_edits.is_empty() {
+        return;
+    }
+    println!("You have {} unsynced edit(s)", state.pending_edits.len());
+    for edit in state.pending_edits {
+        print!(
+            "Unsynced edit for page {} ({}) saved at {}: publish / discard / open / skip?  ",
+            edit.title,
+            edit.id,
+            edit.file_path.display()
+        );
+
+        let user_input: String = text_io::read!("{}\n");
+        match user_input.as_str() {
+            "p" | "publish" => {
+                let mut page = Page::get_page_by_id(&config.api, &edit.id).unwrap();
+                let markdown = crypt::read_to_string(&edit.file_path).unwrap();
+                let (frontmatter, body) = frontmatter::split(&markdown).unwrap();
+                if body.trim().is_empty()
+                    && config.confirm.overwrite
+                    && !confirm_blank_page(&edit.title, &edit.file_path)
+                {
+                    continue;
+                }
+                set_downloaded_version(&mut page, frontmatter.as_ref());
+                if !confirm_remote_unchanged(config, &mut page).unwrap() {
+                    continue;
+                }
+                let changes =
+                    MetadataChanges::between(&config.api, &page, frontmatter.as_ref()).unwrap();
+                let version =
+                    upload_page_by_id(config, &mut page, &edit.file_path, &changes).unwrap();
+                state::remove_pending_edit(&edit.id).unwrap();
+                info!("{}: {}", edit.title, PublishOutcome::Published { version });
+            }
+            "d" | "discard" => state::remove_pending_edit(&edit.id).unwrap(),
+            "o" | "open" => {
+                let mut page = Page::get_page_by_id(&config.api, &edit.id).unwrap();
+                let markdown = crypt::read_to_string(&edit.file_path).unwrap();
+                let (frontmatter, _) = frontmatter::split(&markdown).unwrap();
+                set_downloaded_version(&mut page, frontmatter.as_ref());
+                // The file already differs from the page, so it is offered for
+                // publishing even if it isn't changed again
+                edit_saved_page(config, page, &edit.file_path, None).unwrap();
+            }
+            _ => (),
+        }
+    }
+}
+
+// Winds page back to the version its saved file was downloaded at, so that
+// changes made on Confluence since are caught before publishing the file
+fn set_downloaded_version(page: &mut Page, frontmatter: Option<&Frontmatter>) {
+    if let Some(version) = frontmatter.and_then(|frontmatter| frontmatter.version) {
+        page.version.number = version;
+    }
+}
+
+// Creates an empty page and opens it for editing. With OnConflict::Update an
+// existing page with the same title is opened instead.
+pub fn new_page(
+    config: &Config,
+    title: Option<&str>,
+    space: Option<&str>,
+    on_conflict: &OnConflict,
+) {
+    let title = match (title, &config.title_template) {
+        (Some(title), _) => title.to_string(),
+        (None, Some(template)) => render_title_template(template, None, ""),
+        (None, None) => {
+            eprintln!("Give a title with --title, or a --title-template");
+            std::process::exit(1);
+        }
+    };
+    let space = resolve_space(config, space).unwrap();
+    let id = match resolve_title_conflict(&config.api, &space, &title, on_conflict).unwrap() {
+        TitleResolution::Create(title) => {
+            let id = conf_api::create_page(&config.api, &space.id, &title, None, "").unwrap();
+            audit::record(config, AuditOperation::Create, &id, Some(1), Some(&title));
+            id
+        }
+        TitleResolution::Existing(id) => id,
+    };
+    exit_with(edit_page_by_id(config, &id));
+}
+
+// Creates a new page from a markdown file. If the file has frontmatter from a
+// previous download, the page it names is updated instead, and its space,
+// title and parent are used unless given on the command line. A path of -
+// reads the markdown from stdin, and a glob uploads every file it matches,
+// with a table of the pages at the end.
+pub fn upload_file(
+    config: &Config,
+    path: &Path,
+    title: Option<&str>,
+    space: Option<&str>,
+    on_conflict: &OnConflict,
+    strict: bool,
+    verify: bool,
+) {
+    let sources = upload_sources(config, path).unwrap();
+    if let [source] = &sources[..] {
+        if let Err(e) = upload_one(config, source, title, space, on_conflict, strict, verify) {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if title.is_some() {
+        eprintln!("--title can't be given when uploading several files");
+        std::process::exit(1);
+    }
+
+    let mut results = Vec::new();
+    for source in &sources {
+        let result = upload_one(config, source, None, space, on_conflict, strict, verify);
+        if let Err(e) = &result {
+            eprintln!("{}: {:#}", source.display(), e);
+        }
+        results.push((source, result));
+    }
+    println!("\n{:<12} {:<8} File", "Id", "Result");
+    for (source, result) in &results {
+        let (id, action) = match result {
+            Ok(uploaded) => (uploaded.id.as_str(), uploaded.action),
+            Err(_) => ("-", "failed"),
+        };
+        println!("{:<12} {:<8} {}", id, action, source.display());
+    }
+    if results.iter().any(|(_, result)| result.is_err()) {
+        std::process::exit(1);
+    }
+}
+
+// A page made or changed by upload
+struct Uploaded {
+    id: String,
+    action: &'static str,
+}
+
+// The files an upload path stands for: stdin for -, every match for a glob,
+// the saved file of a page id, or otherwise the path itself
+fn upload_sources(config: &Config, path: &Path) -> Result<Vec<PathBuf>> {
+    if path.exists() || path == Path::new("-") {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let pattern = path.to_string_lossy();
+    if pattern.contains(['*', '?', '[']) {
+        let mut paths = glob::glob(&pattern)?
+            .collect::<Result<Vec<PathBuf>, _>>()?
+            .into_iter()
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        paths.sort();
+        if paths.is_empty() {
+            anyhow::bail!("No files match {}", pattern);
+        }
+        return Ok(paths);
+    }
+    let saved = find_saved_file(config, &pattern);
+    Ok(vec![saved.unwrap_or_else(|| path.to_path_buf())])
+}
+
+fn upload_one(
+    config: &Config,
+    path: &Path,
+    title: Option<&str>,
+    space: Option<&str>,
+    on_conflict: &OnConflict,
+    strict: bool,
+    verify: bool,
+) -> Result<Uploaded> {
+    let markdown = match path == Path::new("-") {
+        true => {
+            let mut markdown = String::new();
+            std::io::stdin().read_to_string(&mut markdown)?;
+            markdown
+        }
+        false => crypt::read_to_string(path)?,
+    };
+    let (frontmatter, body) = frontmatter::split(&markdown)?;
+    let frontmatter = frontmatter.unwrap_or_default();
+    if !print_lint_warnings(path, &markdown, body) && strict {
+        anyhow::bail!("Not uploading {} with --strict", path.display());
+    }
+
+    let frontmatter_space = match frontmatter.page_ref() {
+        PageRef::Existing { id, version } => {
+            let mut page = Page::get_page_by_id(&config.api, id)?;
+            update_uploaded_page(config, &mut page, version, &frontmatter, body)?;
+            info!("Updated page \"{}\" ({})", page.title, id);
+            if verify {
+                verify_published_page(config, id, body)?;
+            }
+            return Ok(Uploaded {
+                id: id.to_string(),
+                action: "updated",
+            });
+        }
+        PageRef::New { space } => space,
+    };
+
+    let space = resolve_space(config, space.or(frontmatter_space))?;
+    let title = match (title.or(frontmatter.title.as_deref()), &config.title_template) {
+        (Some(title), _) => title.to_string(),
+        (None, Some(template)) => render_title_template(template, Some(path), body),
+        (None, None) => match (first_heading(body), path.file_stem()) {
+            (Some(heading), _) => heading,
+            (None, Some(stem)) if path != Path::new("-") => stem.to_string_lossy().to_string(),
+            _ => anyhow::bail!("Give a title with --title, or start the markdown with a # heading"),
+        },
+    };
+    let uploaded = match resolve_title_conflict(&config.api, &space, &title, on_conflict)? {
+        TitleResolution::Create(title) => {
+            let id = conf_api::create_page(
+                &config.api,
+                &space.id,
+                &title,
+                frontmatter.parent.as_deref(),
+                &convert::to_storage(
+                    &mentions::names_to_mentions(&config.api, body, BodyFormat::Storage)?,
+                    Some(config),
+                ),
+            )?;
+            audit::record(config, AuditOperation::Create, &id, Some(1), Some(&title));
+            for label in frontmatter.labels.iter().flatten() {
+                conf_api::add_label(&config.api, &id, label)?;
+                audit::record_detail(config, AuditOperation::Label, &id, label);
+            }
+            info!("Created page \"{}\" ({}) in {}", title, id, space.name);
+            Uploaded {
+                id,
+                action: "created",
+            }
+        }
+        TitleResolution::Existing(id) => {
+            let mut page = Page::get_page_by_id(&config.api, &id)?;
+            update_uploaded_page(config, &mut page, None, &frontmatter, body)?;
+            info!("Updated page \"{}\" ({}) in {}", title, id, space.name);
+            Uploaded {
+                id,
+                action: "updated",
+            }
+        }
+    };
+    if verify {
+        verify_published_page(config, &uploaded.id, body)?;
+    }
+    Ok(uploaded)
+}
+
+// Version number and title of each current page in the space, by page id
+fn remote_versions(config: &Config, space: &Space) -> Result<HashMap<String, (usize, String)>> {
+    Ok(PageSummary::get_pages(&config.api, Some(&space.id), false)?
+        .into_iter()
+        .map(|page| {
+            let version = page.version.as_ref().map_or(0, |version| version.number);
+            (page.id, (version, page.title))
+        })
+        .collect())
+}
+
+// Prints a change seen by watch-remote as json, or runs the exec command for
+// it. A failing command is reported but doesn't stop the watch.
+fn report_remote_change(
+    config: &Config,
+    space: &Space,
+    event: &str,
+    id: &str,
+    title: &str,
+    version: Option<usize>,
+    exec: Option<&str>,
+) {
+    let url = conf_api::page_url(&config.api, id);
+    let Some(exec) = exec else {
+        let change = serde_json::json!({
+            "event": event,
+            "space": space.key,
+            "id": id,
+            "title": title,
+            "version": version,
+            "url": url,
+        });
+        println!("{}", change);
+        return;
+    };
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(exec)
+        .env("CONCMD_EVENT", event)
+        .env("CONCMD_SPACE", &space.key)
+        .env("CONCMD_PAGE_ID", id)
+        .env("CONCMD_PAGE_TITLE", title)
+        .env("CONCMD_PAGE_VERSION", version.map(|v| v.to_string()).unwrap_or_default())
+        .env("CONCMD_PAGE_URL", url)
+        .status();
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => eprintln!("{} for {} {} exited with {}", exec, event, id, status),
+        Err(e) => eprintln!("Could not run {}: {}", exec, e),
+    }
+}
+
+// Content property recording the commit a page was last published from
+const GIT_COMMIT_PROPERTY: &str = "concmd-git-commit";
+
+// Returns the version the page was published as
+fn sync_file(
+    config: &Config,
+    id: &str,
+    frontmatter: &Frontmatter,
+    body: &str,
+    commit: &str,
+    file: &str,
+) -> Result<usize> {
+    let mut page = Page::get_page_by_id(&config.api, id)?;
+    // The repo is the source of truth, and syncing doesn't update the version
+    // in the committed frontmatter, so it isn't checked against Confluence
+    update_uploaded_page(config, &mut page, None, frontmatter, body)?;
+    let value = serde_json::json!({ "commit": commit, "path": file });
+    ContentProperty::set_property(&config.api, id, GIT_COMMIT_PROPERTY, value)?;
+    audit::record_detail(config, AuditOperation::Property, id, GIT_COMMIT_PROPERTY);
+    Ok(page.version.number)
+}
+
+// Runs git in the given directory, returning its stdout
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Fills in a title template like "{filename} — {date}" for a page made from
+// the markdown at path, if any. The git branch and hostname are only looked up
+// when the template uses them.
+fn render_title_template(template: &str, path: Option<&Path>, markdown: &str) -> String {
+    let path = path.filter(|path| *path != Path::new("-"));
+    let filename = path
+        .and_then(Path::file_stem)
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut title = template
+        .replace("{filename}", &filename)
+        .replace("{h1}", &first_heading(markdown).unwrap_or_default())
+        .replace("{date}", &audit::format_timestamp(SystemTime::now())[..10]);
+    if title.contains("{branch}") {
+        let dir = path
+            .and_then(Path::parent)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let branch = command_output(
+            Command::new("git")
+                .arg("-C")
+                .arg(dir)
+                .args(["rev-parse", "--abbrev-ref", "HEAD"]),
+        );
+        title = title.replace("{branch}", &branch);
+    }
+    if title.contains("{hostname}") {
+        title = title.replace("{hostname}", &command_output(&mut Command::new("hostname")));
+    }
+    title.trim().to_string()
+}
+
+// Trimmed stdout of a command, or nothing if it couldn't run or failed
+fn command_output(command: &mut Command) -> String {
+    command
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default()
+}
+
+// The text of the first level 1 heading, used as the title of uploaded files
+// without one in their frontmatter
+fn first_heading(markdown: &str) -> Option<String> {
+    let mut parser = Parser::new(markdown).skip_while(|event| {
+        !matches!(event, Event::Start(Tag::Heading { level: HeadingLevel::H1, .. }))
+    });
+    parser.next()?;
+    let mut heading = String::new();
+    for event in parser {
+        match event {
+            Event::Text(text) | Event::Code(text) => heading.push_str(&text),
+            Event::End(TagEnd::Heading(_)) => break,
+            _ => (),
+        }
+    }
+    Some(heading.trim().to_string()).filter(|heading| !heading.is_empty())
+}
+
+// Uploads the body along with any title, parent and label changes in the
+// frontmatter. Given the version the file was downloaded at, asks before
+// publishing over changes made on Confluence since then.
+pub fn update_uploaded_page(
+    config: &Config,
+    page: &mut Page,
+    downloaded_version: Option<usize>,
+    frontmatter: &Frontmatter,
+    body: &str,
+) -> Result<()> {
+    if let Some(version) = downloaded_version {
+        page.version.number = version;
+        if !confirm_remote_unchanged(config, page)? {
+            anyhow::bail!("Not updating {}, it has changed on Confluence", page.title);
+        }
+    }
+    let changes = MetadataChanges::between(&config.api, page, Some(frontmatter))?;
+    if !changes.is_empty() {
+        info!("Changing the page's metadata:\n{}", changes.to_string().trim_end());
+    }
+    page.set_body(markdown_to_page_body(config, page, body)?);
+    changes.apply_to_page(page);
+    page.update_page_by_id(&config.api)?;
+    record_update(config, page);
+    changes.update_labels(config, &page.id)
+}
+
+// Publishes each markdown file in the repo that changed since the last synced
+// commit, or every one on the first sync, to the page named in its frontmatter
+// and marks the page with the commit. Files are published as committed, so
+// uncommitted changes wait for a commit. The synced commit only moves on once
+// every changed file has been published, so failures are retried next time,
+// and a checkpoint keeps the files already published from going out again.
+pub fn git_sync(config: &Config, repo: &Path, dry_run: bool, restart: bool) {
+    let root = PathBuf::from(git(repo, &["rev-parse", "--show-toplevel"]).unwrap().trim());
+    let head = git(&root, &["rev-parse", "HEAD"]).unwrap().trim().to_string();
+    let last = state::synced_commit(&root).unwrap();
+    if last.as_deref() == Some(head.as_str()) {
+        info!("{} is already synced at {}", root.display(), &head[..7]);
+        return;
+    }
+    // -z so that paths with spaces or non-ascii characters aren't quoted
+    let files = match &last {
+        Some(last) => git(
+            &root,
+            &[
+                "diff",
+                "--name-only",
+                "-z",
+                "--diff-filter=AMR",
+                &format!("{}..{}", last, head),
+                "--",
+                "*.md",
+            ],
+        ),
+        None => git(&root, &["ls-files", "-z", "--", "*.md"]),
+    }
+    .unwrap();
+    // A dry run changes nothing, so there is nothing to resume
+    let mut checkpoint = match dry_run {
+        true => None,
+        false => {
+            let command = format!(
+                "git-sync {} {}..{}",
+                root.display(),
+                last.as_deref().unwrap_or_default(),
+                head
+            );
+            Some(Checkpoint::open(&command, restart).unwrap())
+        }
+    };
+
+    let mut failed = false;
+    for file in files.split('\0').filter(|file| !file.is_empty()) {
+        if checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| checkpoint.is_done(file))
+        {
+            continue;
+        }
+        let result = sync_committed_file(config, &root, &head, file, dry_run);
+        if let Err(e) = &result {
+            eprintln!("{}: {:#}", file, e);
+            failed = true;
+        }
+        if let Some(checkpoint) = &mut checkpoint {
+            match &result {
+                Ok(()) => checkpoint.complete(file),
+                Err(e) => checkpoint.fail(file, &format!("{:#}", e)),
+            }
+            .unwrap();
+        }
+    }
+    let Some(checkpoint) = checkpoint else {
+        return;
+    };
+    checkpoint.finish().unwrap();
+    if failed {
+        eprintln!("Not recording the sync, so the failed files are tried again next time");
+        std::process::exit(1);
+    }
+    state::set_synced_commit(&root, &head).unwrap();
+    info!("Synced {} at {}", root.display(), &head[..7]);
+}
+
+// Publishes file as it is at commit, if its frontmatter names a page
+fn sync_committed_file(
+    config: &Config,
+    root: &Path,
+    commit: &str,
+    file: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let markdown = git(root, &["show", &format!("{}:{}", commit, file)])?;
+    let (frontmatter, body) = frontmatter::split(&markdown)?;
+    let frontmatter = frontmatter.unwrap_or_default();
+    let PageRef::Existing { id, .. } = frontmatter.page_ref() else {
+        info!("Skipping {}: no page id in its frontmatter", file);
+        return Ok(());
+    };
+    if dry_run {
+        println!("Would publish {} to page {}", file, id);
+        return Ok(());
+    }
+    let version = sync_file(config, id, &frontmatter, body, commit, file)?;
+    info!("Published {} as version {} of page {}", file, version, id);
+    Ok(())
+}
+
+// Compares the page on Confluence with the markdown it was published from,
+// by default the page's saved file. Exits with an error if content was lost.
+pub fn verify_page(config: &Config, id: &str, path: Option<&Path>) {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => find_saved_file(config, id).unwrap_or_else(|| {
+            eprintln!("No saved file for page {}, give one with --path", id);
+            std::process::exit(1);
+        }),
+    };
+    let markdown = crypt::read_to_string(&path).unwrap();
+    let (_, body) = frontmatter::split(&markdown).unwrap();
+    if !verify_published_page(config, id, body).unwrap() {
+        std::process::exit(1);
+    }
+}
+
+// Prints every operation in the plan, then asks before running them in order.
+// Stops at the first failed operation so later steps never run against a
+// half-applied plan.
+pub fn apply_plan(config: &Config, space_key: &str, plan_path: &Path, dry_run: bool) {
+    let operations = plan::read_plan(plan_path).unwrap();
+    let space = Space::get_space_by_key(&config.api, space_key).unwrap();
+    println!("Plan for space {} ({}):", space.name, space.key);
+    for (i, operation) in operations.iter().enumerate() {
+        println!("  {}. {}", i + 1, operation);
+    }
+    let problems = check_renames(&config.api, &space, &operations).unwrap();
+    if !problems.is_empty() {
+        eprintln!("The plan can't be applied:");
+        for problem in problems {
+            eprintln!("  {}", problem);
+        }
+        std::process::exit(1);
+    }
+    if dry_run || (config.confirm.bulk && !confirm("Do you wish to apply this plan")) {
+        return;
+    }
+    for operation in &operations {
+        info!("Running: {}", operation);
+        run_operation(config, &space, operation).unwrap();
+    }
+}
+
+// Loads every page title (optionally limited to one space) and lets the user
+// fuzzy search down to a single page. Prints the page id, or opens the page
+// for editing if edit is set. Matches from every space show their space key.
+pub fn pick_page(config: &Config, space_key: Option<&str>, edit: bool, include_archived: bool) {
+    let space_id = space_key.map(|key| Space::get_space_by_key(&config.api, key).unwrap().id);
+    // Listing every page on the site takes a while, so it is fetched in the
+    // background. With cache_pages the listing saved last time is searched
+    // until the fresh one arrives; it is only kept then, as it leaves titles
+    // on disk.
+    let refresh = {
+        let api = config.api.clone();
+        let space_id = space_id.clone();
+        std::thread::spawn(move || -> Result<Vec<PageSummary>> {
+            let pages = PageSummary::get_pages(&api, space_id.as_deref(), include_archived)?;
+            if api.cache_pages {
+                // The cache is only an optimisation, failing to write it isn't
+                // an error
+                let space_id = space_id.as_deref();
+                let _ = conf_api::save_page_list(&api, space_id, include_archived, &pages);
+            }
+            Ok(pages)
+        })
+    };
+    let cached = match config.api.cache_pages {
+        true => conf_api::read_page_list(&config.api, space_id.as_deref(), include_archived),
+        false => None,
+    };
+    let spaces = match space_key {
+        Some(_) => Vec::new(),
+        None => Space::get_spaces(&config.api).unwrap(),
+    };
+    let (mut pages, mut refresh) = match cached {
+        Some(pages) => (pages, Some(refresh)),
+        None => {
+            let pages = refresh.join().expect("page listing thread panicked");
+            (pages.unwrap(), None)
+        }
+    };
+    let space_keys: HashMap<String, String> = spaces
+        .into_iter()
+        .map(|space| (space.id, space.key))
+        .collect();
+    let matcher = SkimMatcherV2::default();
+
+    let page = loop {
+        if let Some(refresh) = refresh.take_if(|refresh| refresh.is_finished()) {
+            match refresh.join().expect("page listing thread panicked") {
+                Ok(fresh) => pages = fresh,
+                Err(e) => eprintln!("Warning: Couldn't refresh the page titles: {:#}", e),
+            }
+        }
+        print!("Search: ");
+        let query: String = text_io::read!("{}\n");
+        let mut matches: Vec<(i64, &PageSummary)> = pages
+            .iter()
+            .filter_map(|page| matcher.fuzzy_match(&page.title, &query).map(|score| (score, page)))
+            .collect();
+        matches.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        matches.truncate(10);
+        if matches.is_empty() {
+            info!("No pages match \"{}\"", query);
+            continue;
+        }
+        for (i, (_, page)) in matches.iter().enumerate() {
+            let space = page
+                .space_id
+                .as_ref()
+                .and_then(|id| space_keys.get(id))
+                .map(|key| format!("{} · ", key))
+                .unwrap_or_default();
+            let other_columns = format!(
+                "  {}. {} ({}){}",
+                i + 1,
+                space,
+                page.id,
+                archived_marker(page)
+            );
+            println!(
+                "  {}. {}{} ({}){}",
+                i + 1,
+                space,
+                fit_title(&page.title, other_columns.width()),
+                page.id,
+                archived_marker(page)
+            );
+        }
+        print!("Select a page number, or press enter to search again:  ");
+        let selection: String = text_io::read!("{}\n");
+        match selection.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= matches.len() => break matches[n - 1].1,
+            _ => continue,
+        }
+    };
+
+    if edit {
+        exit_with(edit_page_by_id(config, &page.id));
+    } else {
+        println!("{}", page.id);
+    }
+}
+
+// Searches page titles in every space with CQL. Prints the matches, or lets
+// the user pick one to edit if edit is set.
+pub fn search_pages(config: &Config, query: &str, edit: bool) {
+    let cql = format!("type = page and title ~ {}", conf_api::cql_string(query));
+    let results = Content::search(&config.api, &cql).unwrap();
+    if results.is_empty() {
+        info!("No pages match \"{}\"", query);
+        return;
+    }
+    let listing: Vec<(&str, &str, &str)> = results
+        .iter()
+        .map(|page| {
+            let space = page.space.as_ref().map_or("?", |space| space.key.as_str());
+            (space, page.title.as_str(), page.id.as_str())
+        })
+        .collect();
+    if let Some(id) = choose_page(&listing, edit) {
+        exit_with(edit_page_by_id(config, id));
+    }
+}
+
+// Lists the pages titled exactly title, in one space or all of them. With pick
+// the user chooses one to edit, or it is opened straight away if it is the only
+// match.
+pub fn find_pages(
+    config: &Config,
+    title: &str,
+    space_key: Option<&str>,
+    pick: bool,
+) -> Result<CommandOutcome> {
+    let space = match space_key {
+        Some(key) => Some(Space::get_space_by_key_or_id(&config.api, key)?),
+        None => None,
+    };
+    let space_id = space.as_ref().map(|space| space.id.as_str());
+    let pages = PageSummary::get_pages_by_title(&config.api, space_id, title)?;
+    let space_keys: HashMap<String, String> = match space {
+        Some(space) => HashMap::from([(space.id, space.key)]),
+        None => Space::get_spaces(&config.api)?
+            .into_iter()
+            .map(|space| (space.id, space.key))
+            .collect(),
+    };
+    if pages.is_empty() {
+        let reason = format!("No pages titled \"{}\"", title);
+        return Ok(CommandOutcome::Failed(reason));
+    }
+    if let ([page], true) = (pages.as_slice(), pick) {
+        return edit_page_by_id(config, &page.id);
+    }
+    let listing: Vec<(&str, &str, &str)> = pages
+        .iter()
+        .map(|page| {
+            let space = page
+                .space_id
+                .as_ref()
+                .and_then(|id| space_keys.get(id))
+                .map_or("?", String::as_str);
+            (space, page.title.as_str(), page.id.as_str())
+        })
+        .collect();
+    match choose_page(&listing, pick) {
+        Some(id) => edit_page_by_id(config, id),
+        None => Ok(CommandOutcome::Done),
+    }
+}
+
+// Prints a numbered list of pages, given as (space key, title, id), with the
+// titles cut to fit the terminal. With pick the user then chooses one by its
+// number, and its id is returned.
+fn choose_page<'a>(pages: &[(&str, &str, &'a str)], pick: bool) -> Option<&'a str> {
+    for (i, (space, title, id)) in pages.iter().enumerate() {
+        let other_columns = format!("  {}. {} ·  ({})", i + 1, space, id).width();
+        println!(
+            "  {}. {} · {} ({})",
+            i + 1,
+            space,
+            fit_title(title, other_columns),
+            id
+        );
+    }
+    if !pick {
+        return None;
+    }
+    loop {
+        print!("Select a page number to edit:  ");
+        let selection: String = text_io::read!("{}\n");
+        match selection.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= pages.len() => return Some(pages[n - 1].2),
+            _ => continue,
+        }
+    }
+}
+
+pub fn run_cql(config: &Config, cql: &str, json: bool) {
+    let results = Content::search(&config.api, cql).unwrap();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results).unwrap());
+        return;
+    }
+    for content in results {
+        let space = content.space.as_ref().map_or("?", |space| &space.key);
+        let columns = format!(
+            "{} {:<ID_COLUMN$} {:<KEY_COLUMN$} ",
+            content.content_type.icon(),
+            content.id,
+            space
+        );
+        println!("{}{}", columns, fit_title(&content.title, columns.width()));
+    }
+}
+
+// Prints a summary of the page, optionally copying its id, web url or body as
+// markdown to the clipboard
+pub fn page_info(config: &Config, id: &str, copy: Option<&CopyTarget>) {
+    let page = Page::get_page_by_id(&config.api, id).unwrap();
+    let space = Space::get_space_by_id(&config.api, &page.space_id).unwrap();
+    let url = conf_api::page_url(&config.api, &page.id);
+    println!("Title:   {}", page.title);
+    println!("Id:      {}", page.id);
+    println!("Space:   {} ({})", space.name, space.key);
+    let ancestors = conf_api::get_ancestors(&config.api, &page.id).unwrap();
+    let path: Vec<&str> = std::iter::once(space.key.as_str())
+        .chain(ancestors.iter().map(|ancestor| ancestor.title.as_str()))
+        .chain(std::iter::once(page.title.as_str()))
+        .collect();
+    println!("Path:    {}", path.join(" › "));
+    println!("Version: {}", page.version.number);
+    println!("Editor:  {}", editor_name(&page));
+    println!("Labels:  {}", page.get_labels(&config.api).unwrap().join(", "));
+    println!("Url:     {}", url);
+
+    if let Some(copy) = copy {
+        let (text, what) = match copy {
+            CopyTarget::Id => (page.id.clone(), "page id"),
+            CopyTarget::Url => (url, "url"),
+            CopyTarget::Body => (
+                page_to_markdown(config, &page).unwrap(),
+                "body",
+            ),
+        };
+        copy_to_clipboard(&text).unwrap();
+        info!("Copied the {} to the clipboard", what);
+    }
+}
+
+// Runs in the background started by copy_to_clipboard, holding the text from
+// stdin on the clipboard until something else is copied
+#[cfg(target_os = "linux")]
+pub fn hold_clipboard() {
+    use arboard::SetExtLinux;
+
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text).unwrap();
+    arboard::Clipboard::new().unwrap().set().wait().text(text).unwrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn hold_clipboard() {}
+
+// Renders the page as markdown straight into the pager, without saving it to
+// the save location or opening the editor. A preview prints the start of the
+// page to stdout instead.
+pub fn read_page_by_id(config: &Config, id: &str, preview: Option<&PreviewLength>) {
+    let page = Page::get_page_by_id(&config.api, id).unwrap();
+    let body = page_to_markdown(config, &page).unwrap();
+    let markdown = format!("# {}\n\n{}", page.title, body);
+    let Some(preview) = preview else {
+        return show_in_pager(&markdown).unwrap();
+    };
+    let preview = truncate_markdown(&markdown, preview);
+    let (width, _) = termimad::terminal_size();
+    print!("{}", termimad::MadSkin::default().text(&preview, Some(width as usize)));
+}
+
+// Prints the space's content tree from its homepage down, including the
+// folders, whiteboards and databases that page listings leave out
+pub fn print_space_tree(config: &Config, space_key: &str) {
+    let space = Space::get_space_by_key(&config.api, space_key).unwrap();
+    let homepage = Content {
+        id: space.homepage_id.expect("space should have a homepage"),
+        title: space.name,
+        space: None,
+        content_type: ContentType::Page,
+    };
+    print_content_tree(&config.api, &homepage, 0).unwrap();
+}
+
+// Lists the spaces whose name or key contains the filter, ignoring case, in
+// the order last given with --sort, which is remembered for the next run
+pub fn list_spaces(
+    config: &Config,
+    filter: Option<&str>,
+    sort: Option<SpaceSort>,
+    label: Option<&str>,
+) {
+    let state = State::load().unwrap();
+    let sort = match sort {
+        Some(sort) => {
+            state::set_space_sort(sort).unwrap();
+            sort
+        }
+        None => state.space_sort,
+    };
+    let spaces = match label {
+        Some(label) => Space::get_spaces_with_label(&config.api, label),
+        None => Space::get_spaces(&config.api),
+    };
+    let filter = filter.map(str::to_lowercase);
+    let mut spaces: Vec<Space> = spaces
+        .unwrap()
+        .into_iter()
+        .filter(|space| {
+            filter.as_ref().is_none_or(|filter| {
+                space.name.to_lowercase().contains(filter)
+                    || space.key.to_lowercase().contains(filter)
+            })
+        })
+        .collect();
+    match &filter {
+        Some(filter) => info!("{} spaces matching \"{}\":", spaces.len(), filter),
+        None => info!("{} spaces:", spaces.len()),
+    }
+    sort_spaces(&mut spaces, sort, &state.recent_spaces);
+    for space in spaces {
+        let columns = format!("  {:<KEY_COLUMN$} ", space.key);
+        println!("{}{}", columns, fit_title(&space.name, columns.width()));
+    }
+}
+
+// Prints every page in the space without any interaction. Plain output is
+// tab separated for grep and fzf; otherwise it is lined up for reading.
+pub fn view_space(config: &Config, space_key: &str, plain: bool, include_archived: bool) {
+    let space = Space::get_space_by_key(&config.api, space_key).unwrap();
+    let pages = PageSummary::get_pages(&config.api, Some(&space.id), include_archived).unwrap();
+    if !plain {
+        println!(
+            "{:<ID_COLUMN$} {:<DATE_COLUMN$} {:<DATE_COLUMN$} Title",
+            "Id", "Created", "Updated"
+        );
+    }
+    for page in pages {
+        let created = page.created_at.as_deref().unwrap_or("-");
+        let updated = page
+            .version
+            .as_ref()
+            .and_then(|version| version.created_at.as_deref())
+            .unwrap_or("-");
+        if plain {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                page.id, page.title, created, updated, page.status
+            );
+        } else {
+            // Dates only, the full timestamps are too noisy to read
+            let columns = format!(
+                "{:<ID_COLUMN$} {:<DATE_COLUMN$} {:<DATE_COLUMN$} ",
+                page.id,
+                created.get(..10).unwrap_or(created),
+                updated.get(..10).unwrap_or(updated)
+            );
+            let marker = archived_marker(&page);
+            println!(
+                "{}{}{}",
+                columns,
+                fit_title(&page.title, columns.width() + marker.width()),
+                marker
+            );
+        }
+    }
+}
+
+// Lists the open tasks in a space and lets the user pick any to mark complete
+pub fn list_open_tasks(config: &Config, space_key: &str, mine: bool) {
+    let space = Space::get_space_by_key(&config.api, space_key).unwrap();
+    let assigned_to = if mine {
+        Some(User::get_current_user(&config.api).unwrap().account_id)
+    } else {
+        None
+    };
+    let tasks = Task::get_open_tasks(&config.api, &space.id, assigned_to.as_deref()).unwrap();
+    if tasks.is_empty() {
+        info!("No open tasks in {}", space.name);
+        return;
+    }
+    for (i, task) in tasks.iter().enumerate() {
+        println!(
+            "  {}. {} (page {})",
+            i + 1,
+            convert::to_markdown(task.get_body(), Some(config)).trim(),
+            task.page_id.as_deref().unwrap_or("-")
+        );
+    }
+    print!("Enter the numbers of any tasks to mark complete, or press enter to skip:  ");
 
     let user_input: String = text_io::read!("{}\n");
-    match user_input.as_str() {
-        "y" | "Y" | "yes" | "Yes" => upload_page_by_id(&config.api, &mut page, &file_path).unwrap(),
-        _ => (),
+    for n in user_input
+        .split_whitespace()
+        .filter_map(|n| n.parse::<usize>().ok())
+    {
+        match n.checked_sub(1).and_then(|i| tasks.get(i)) {
+            Some(task) => {
+                task.complete(&config.api).unwrap();
+                let page_id = task.page_id.as_deref().unwrap_or("-");
+                let detail = format!("task {}", task.id);
+                audit::record_detail(config, AuditOperation::Complete, page_id, &detail);
+                info!("Completed task {}", n);
+            }
+            None => println!("There is no task {}", n),
+        }
+    }
+}
+
+// Converts stdin between markdown, storage format and ADF and writes the result
+// to stdout, using the same pipeline as downloads and uploads. Storage format
+// and ADF are converted to each other by way of markdown.
+pub fn convert_stdin(config: Option<&Config>, from: &Format, to: &Format) {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).unwrap();
+    if from == to {
+        std::io::stdout().write_all(input.as_bytes()).unwrap();
+        return;
+    }
+    let markdown = match from {
+        Format::Md => input,
+        Format::Storage => convert::to_markdown(&input, config),
+        Format::Adf => adf::to_markdown(&input).unwrap(),
+    };
+    let output = match to {
+        Format::Md => markdown,
+        Format::Storage => convert::to_storage(&markdown, config),
+        Format::Adf => adf::from_markdown(&markdown).unwrap(),
+    };
+    std::io::stdout().write_all(output.as_bytes()).unwrap();
+}
+
+// Adds any requested view/edit restrictions to the page, then prints the
+// page's current restrictions
+pub fn restrict_page(config: &Config, id: &str, view: &[Restriction], edit: &[Restriction]) {
+    for restriction in view {
+        PageRestrictions::add_restriction(&config.api, id, "read", restriction).unwrap();
+        let detail = format!("view {}", restriction);
+        audit::record_detail(config, AuditOperation::Restrict, id, &detail);
+    }
+    for restriction in edit {
+        PageRestrictions::add_restriction(&config.api, id, "update", restriction).unwrap();
+        let detail = format!("edit {}", restriction);
+        audit::record_detail(config, AuditOperation::Restrict, id, &detail);
+    }
+    let restrictions = PageRestrictions::get_restrictions(&config.api, id).unwrap();
+    print_operation_restrictions("View", &restrictions.read);
+    print_operation_restrictions("Edit", &restrictions.update);
+}
+
+pub fn watch(config: &Config, target: &WatchTarget) {
+    target.watch(&config.api).unwrap();
+    record_watch(config, AuditOperation::Watch, target);
+    info!("Watching {}", target);
+}
+
+pub fn unwatch(config: &Config, target: &WatchTarget) {
+    target.unwatch(&config.api).unwrap();
+    record_watch(config, AuditOperation::Unwatch, target);
+    info!("Stopped watching {}", target);
+}
+
+fn record_watch(config: &Config, operation: AuditOperation, target: &WatchTarget) {
+    match target {
+        WatchTarget::Page(id) => audit::record_detail(config, operation, id, "page"),
+        WatchTarget::Space(key) => audit::record_detail(config, operation, key, "space"),
+    }
+}
+
+// There is no endpoint listing watched spaces, so every space is checked in
+// turn. Watched pages can be found with CQL.
+pub fn list_watched(config: &Config) {
+    println!("Watched pages:");
+    for page in Content::search(&config.api, "watcher = currentUser() and type = page").unwrap() {
+        let space = page.space.as_ref().map_or("?", |space| &space.key);
+        println!("  {} · {} ({})", space, page.title, page.id);
+    }
+    println!("Watched spaces:");
+    for space in Space::get_spaces(&config.api).unwrap() {
+        if WatchTarget::Space(space.key.clone())
+            .is_watching(&config.api)
+            .unwrap()
+        {
+            println!("  {} ({})", space.name, space.key);
+        }
+    }
+}
+
+// Removes saved pages (and editor backup files) from the save location,
+// keeping any with edits that haven't been published yet
+pub fn clean_save_location(config: &Config, older_than: Option<Duration>, dry_run: bool) {
+    let pending: Vec<PathBuf> = State::load()
+        .unwrap()
+        .pending_edits
+        .into_iter()
+        .map(|edit| edit.file_path)
+        .collect();
+    let now = SystemTime::now();
+    for entry in std::fs::read_dir(&config.save_location).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if !(name.ends_with(".md") || name.ends_with('~')) || pending.contains(&path) {
+            continue;
+        }
+        if let Some(older_than) = older_than {
+            let modified = path.metadata().and_then(|metadata| metadata.modified()).unwrap();
+            if now.duration_since(modified).unwrap_or_default() < older_than {
+                continue;
+            }
+        }
+        if dry_run {
+            println!("Would remove {}", path.display());
+        } else {
+            std::fs::remove_file(&path).unwrap();
+            info!("Removed {}", path.display());
+        }
+    }
+}
+
+// Summarises a space for doc gardening: how many pages it has, which haven't
+// been touched in a while, the largest and most edited pages, and who created
+// them
+pub fn space_stats(config: &Config, space_key: &str, untouched_for: Duration, json: bool) {
+    // Length of each top list in the text output
+    const TOP: usize = 10;
+
+    let space = Space::get_space_by_key_or_id(&config.api, space_key).unwrap();
+    let pages = PageSummary::get_pages(&config.api, Some(&space.id), false).unwrap();
+    let sizes = PageSummary::get_page_sizes(&config.api, &space.id).unwrap();
+    let now = SystemTime::now();
+
+    let mut untouched: Vec<&PageSummary> = pages
+        .iter()
+        .filter(|page| {
+            last_updated(page).is_some_and(|updated| {
+                now.duration_since(updated).unwrap_or_default() >= untouched_for
+            })
+        })
+        .collect();
+    untouched.sort_by_key(|page| last_updated(page));
+
+    let mut largest: Vec<(&PageSummary, usize)> = pages
+        .iter()
+        .map(|page| (page, sizes.get(&page.id).copied().unwrap_or(0)))
+        .collect();
+    largest.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+    let mut most_versioned: Vec<(&PageSummary, usize)> = pages
+        .iter()
+        .map(|page| (page, page.version.as_ref().map_or(0, |version| version.number)))
+        .collect();
+    most_versioned.sort_by_key(|(_, versions)| std::cmp::Reverse(*versions));
+
+    let mut author_counts: HashMap<&str, usize> = HashMap::new();
+    for author in pages.iter().filter_map(|page| page.author_id.as_deref()) {
+        *author_counts.entry(author).or_default() += 1;
+    }
+    let mut authors: Vec<(String, usize)> = author_counts
+        .into_iter()
+        .map(|(id, count)| {
+            // Accounts that have since been deleted can't be looked up
+            let name = User::get_user(&config.api, id)
+                .map(|user| user.display_name)
+                .unwrap_or_else(|_| id.to_string());
+            (name, count)
+        })
+        .collect();
+    authors.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    if json {
+        let summary = serde_json::json!({
+            "space": space.key,
+            "pages": pages.len(),
+            "untouched": untouched
+                .iter()
+                .map(|page| serde_json::json!({
+                    "id": page.id,
+                    "title": page.title,
+                    "updated": page.version.as_ref().and_then(|version| version.created_at.as_deref()),
+                }))
+                .collect::<Vec<_>>(),
+            "largest": largest
+                .iter()
+                .take(TOP)
+                .map(|(page, size)| serde_json::json!({"id": page.id, "title": page.title, "bytes": size}))
+                .collect::<Vec<_>>(),
+            "most_versioned": most_versioned
+                .iter()
+                .take(TOP)
+                .map(|(page, versions)| serde_json::json!({"id": page.id, "title": page.title, "versions": versions}))
+                .collect::<Vec<_>>(),
+            "authors": authors
+                .iter()
+                .map(|(name, count)| serde_json::json!({"name": name, "pages": count}))
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+        return;
+    }
+
+    println!("{} ({}): {} pages", space.name, space.key, pages.len());
+    println!(
+        "\nUntouched for {} days: {}",
+        untouched_for.as_secs() / (24 * 60 * 60),
+        untouched.len()
+    );
+    for page in untouched.iter().take(TOP) {
+        let updated = page
+            .version
+            .as_ref()
+            .and_then(|version| version.created_at.as_deref())
+            .unwrap_or("-");
+        println!("  {:<10}  {} ({})", updated.get(..10).unwrap_or(updated), page.title, page.id);
+    }
+    println!("\nLargest pages:");
+    for (page, size) in largest.iter().take(TOP) {
+        println!("  {:>7.1} KB  {} ({})", *size as f64 / 1024.0, page.title, page.id);
+    }
+    println!("\nMost versioned pages:");
+    for (page, versions) in most_versioned.iter().take(TOP) {
+        println!("  {:>5}  {} ({})", versions, page.title, page.id);
+    }
+    println!("\nPages by author:");
+    for (name, count) in &authors {
+        println!("  {:>5}  {}", count, name);
+    }
+}
+
+// Lists pages not updated for longer than the threshold, oldest first, with
+// their owner and a link. Optionally labels each one or comments on it so the
+// owners get notified.
+pub fn stale_pages(
+    config: &Config,
+    space_key: &str,
+    threshold: Duration,
+    label: Option<&str>,
+    add_label: Option<&str>,
+    comment: Option<&str>,
+) {
+    let space = Space::get_space_by_key_or_id(&config.api, space_key).unwrap();
+    let pages = PageSummary::get_pages(&config.api, Some(&space.id), false).unwrap();
+    let now = SystemTime::now();
+    let mut stale: Vec<&PageSummary> = pages
+        .iter()
+        .filter(|page| {
+            last_updated(page).is_some_and(|updated| {
+                now.duration_since(updated).unwrap_or_default() >= threshold
+            })
+        })
+        .collect();
+    // Labels take a request per page, so only check the pages already found
+    // to be stale
+    if let Some(label) = label {
+        stale.retain(|page| {
+            conf_api::get_labels(&config.api, &page.id)
+                .unwrap()
+                .iter()
+                .any(|page_label| page_label == label)
+        });
+    }
+    stale.sort_by_key(|page| last_updated(page));
+
+    let comment = comment.map(|comment| convert::to_storage(comment, Some(config)));
+    let mut owners: HashMap<String, String> = HashMap::new();
+    for page in stale {
+        let owner = match page.owner_id.as_ref().or(page.author_id.as_ref()) {
+            Some(id) => owners
+                .entry(id.clone())
+                .or_insert_with(|| {
+                    User::get_user(&config.api, id)
+                        .map(|user| user.display_name)
+                        .unwrap_or_else(|_| id.clone())
+                })
+                .clone(),
+            None => "-".to_string(),
+        };
+        let updated = page
+            .version
+            .as_ref()
+            .and_then(|version| version.created_at.as_deref())
+            .unwrap_or("-");
+        println!(
+            "{:<10}  {}  {}  {}",
+            updated.get(..10).unwrap_or(updated),
+            page.title,
+            owner,
+            conf_api::page_url(&config.api, &page.id)
+        );
+        if let Some(add_label) = add_label {
+            conf_api::add_label(&config.api, &page.id, add_label).unwrap();
+            audit::record_detail(config, AuditOperation::Label, &page.id, add_label);
+        }
+        if let Some(comment) = &comment {
+            conf_api::add_comment(&config.api, &page.id, comment).unwrap();
+            audit::record_detail(config, AuditOperation::Comment, &page.id, comment);
+        }
+    }
+}
+
+// Prints the property's json value, or exits with an error if the page
+// doesn't have it
+pub fn get_property(config: &Config, page_id: &str, key: &str) {
+    match ContentProperty::get_property(&config.api, page_id, key).unwrap() {
+        Some(property) => println!("{}", property.value),
+        None => {
+            eprintln!("Page {} has no property {}", page_id, key);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn set_property(config: &Config, page_id: &str, key: &str, value: &str) {
+    let value = serde_json::from_str(value)
+        .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+    ContentProperty::set_property(&config.api, page_id, key, value).unwrap();
+    audit::record_detail(config, AuditOperation::Property, page_id, key);
+}
+
+pub fn list_properties(config: &Config, page_id: &str) {
+    for property in ContentProperty::get_properties(&config.api, page_id).unwrap() {
+        println!("{} = {}", property.key, property.value);
+    }
+}
+
+// Replaces text in every page of the space, showing each match and asking
+// before updating the page. The replacement is made in the page body as
+// stored, so links and macro parameters are covered too.
+pub fn replace_in_space(
+    config: &Config,
+    space_key: &str,
+    find: &str,
+    replacement: &str,
+    is_regex: bool,
+    dry_run: bool,
+    restart: bool,
+) {
+    let space = Space::get_space_by_key_or_id(&config.api, space_key).unwrap();
+    let pattern = match is_regex {
+        true => Regex::new(find).unwrap(),
+        false => Regex::new(&regex::escape(find)).unwrap(),
+    };
+    let replacement = Replacement {
+        pattern,
+        replacement,
+        is_regex,
+        message: format!("Replaced \"{}\" with \"{}\"", find, replacement),
+    };
+    // A dry run changes nothing, so there is nothing to resume
+    let mut checkpoint = match dry_run {
+        true => None,
+        false => {
+            let command = format!(
+                "replace {} {} {} {}",
+                space.id, is_regex, find, replacement.replacement
+            );
+            Some(Checkpoint::open(&command, restart).unwrap())
+        }
+    };
+    let mut confirm_all = dry_run || !config.confirm.bulk;
+    let mut matched = 0;
+    let mut updated = 0;
+    let mut failed = 0;
+    let mut quit = false;
+    for summary in PageSummary::get_pages(&config.api, Some(&space.id), false).unwrap() {
+        if checkpoint.as_ref().is_some_and(|c| c.is_done(&summary.id)) {
+            continue;
+        }
+        let result = replace_in_page(config, &summary.id, &replacement, dry_run, &mut confirm_all);
+        let checkpointed = match (&mut checkpoint, &result) {
+            (None, _) => Ok(()),
+            (_, Ok(Replaced::Quit)) => Ok(()),
+            (Some(checkpoint), Err(e)) => checkpoint.fail(&summary.id, &format!("{:#}", e)),
+            (Some(checkpoint), Ok(_)) => checkpoint.complete(&summary.id),
+        };
+        checkpointed.unwrap();
+        match result {
+            Ok(Replaced::Unmatched) => (),
+            Ok(Replaced::Skipped) => matched += 1,
+            Ok(Replaced::Updated) => {
+                matched += 1;
+                updated += 1;
+            }
+            Ok(Replaced::Quit) => {
+                matched += 1;
+                quit = true;
+                break;
+            }
+            Err(e) => {
+                eprintln!("Failed to update {} ({}): {:#}", summary.title, summary.id, e);
+                failed += 1;
+            }
+        }
+    }
+    match dry_run {
+        true => info!("\n{} page(s) would be changed", matched),
+        false => info!("\nUpdated {} of {} matching page(s)", updated, matched),
+    }
+    // Quitting leaves the checkpoint in place so the rest can be done later
+    if let Some(checkpoint) = checkpoint.filter(|_| !quit) {
+        checkpoint.finish().unwrap();
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+struct Replacement<'a> {
+    pattern: Regex,
+    replacement: &'a str,
+    is_regex: bool,
+    message: String,
+}
+
+enum Replaced {
+    Unmatched,
+    Skipped,
+    Updated,
+    Quit,
+}
+
+fn replace_in_page(
+    config: &Config,
+    id: &str,
+    replacement: &Replacement,
+    dry_run: bool,
+    confirm_all: &mut bool,
+) -> Result<Replaced> {
+    let Replacement {
+        pattern,
+        replacement,
+        is_regex,
+        message,
+    } = replacement;
+    let mut page = Page::get_page_by_id(&config.api, id)?;
+    let body = page.get_body().clone();
+    let new_body = replace_in_body(&body, page.body_format(), pattern, replacement, *is_regex)?;
+    if new_body == body {
+        return Ok(Replaced::Unmatched);
+    }
+    println!("\n{} ({})", page.title, page.id);
+    print_replacements(&body, pattern, replacement, *is_regex);
+    if dry_run {
+        return Ok(Replaced::Skipped);
+    }
+    if !*confirm_all {
+        print!("Update this page: y(es) / n(o) / a(ll) / q(uit)?  ");
+        let user_input: String = text_io::read!("{}\n");
+        match user_input.as_str() {
+            "y" | "yes" => (),
+            "a" | "all" => *confirm_all = true,
+            "q" | "quit" => return Ok(Replaced::Quit),
+            _ => return Ok(Replaced::Skipped),
+        }
+    }
+    page.set_body(new_body);
+    page.version.message = Some(message.clone());
+    page.update_page_by_id(&config.api)?;
+    record_update(config, &page);
+    Ok(Replaced::Updated)
+}
+
+pub fn archive_page(config: &Config, id: &str) {
+    conf_api::archive_page(&config.api, id).unwrap();
+    audit::record(config, AuditOperation::Archive, id, None, None);
+    info!("Archiving page {}", id);
+}
+
+pub fn unarchive_page(config: &Config, id: &str) {
+    conf_api::unarchive_page(&config.api, id).unwrap();
+    audit::record(config, AuditOperation::Unarchive, id, None, None);
+    info!("Restored page {}", id);
+}
+
+// Purging can't be undone, so by default it asks for the title to be typed out
+// as well as shown, as a y/n answer is too easy to give without reading
+pub fn delete_page(config: &Config, id: &str, purge: bool) {
+    let page = PageSummary::get_page_summary(&config.api, id).unwrap();
+    let confirmed = match (purge, config.confirm.type_title_to_purge) {
+        _ if !config.confirm.delete => true,
+        (true, true) => {
+            print!(
+                "Permanently deleting \"{}\" can't be undone. Type the page title to confirm:  ",
+                page.title
+            );
+            let typed: String = text_io::read!("{}\n");
+            typed.trim() == page.title.trim()
+        }
+        (true, false) => confirm(&format!("Permanently delete \"{}\"", page.title)),
+        (false, _) => confirm(&format!("Move \"{}\" to the trash", page.title)),
+    };
+    if !confirmed {
+        println!("Not deleting \"{}\"", page.title);
+        return;
+    }
+    match purge {
+        true => {
+            conf_api::purge_page(&config.api, id).unwrap();
+            audit::record(config, AuditOperation::Purge, id, None, Some(&page.title));
+            info!("Permanently deleted \"{}\"", page.title);
+        }
+        false => {
+            conf_api::delete_page(&config.api, id).unwrap();
+            audit::record(config, AuditOperation::Delete, id, None, Some(&page.title));
+            info!("Moved \"{}\" to the trash", page.title);
+        }
+    }
+}
+
+// Saves every page in the space as markdown in dir, the same as pages saved
+// for editing but never encrypted. Pages are fetched, converted and written by
+// a pool of jobs workers. A page that fails is reported at the end rather than
+// stopping the others, and is retried when the export is run again.
+pub fn export_space(
+    config: &Config,
+    space_key: &str,
+    dir: &Path,
+    jobs: Option<usize>,
+    restart: bool,
+) {
+    let space = Space::get_space_by_key_or_id(&config.api, space_key).unwrap();
+    let pages = PageSummary::get_pages(&config.api, Some(&space.id), false).unwrap();
+    std::fs::create_dir_all(dir).unwrap();
+    let dir = dir.canonicalize().unwrap();
+    let command = format!("export {} {}", space.id, dir.display());
+    let checkpoint = Checkpoint::open(&command, restart).unwrap();
+    let pending: Vec<&PageSummary> = pages
+        .iter()
+        .filter(|page| !checkpoint.is_done(&page.id))
+        .collect();
+    let jobs = jobs
+        .unwrap_or(config.export_jobs)
+        .clamp(1, pending.len().max(1));
+
+    let next = AtomicUsize::new(0);
+    let exported = AtomicUsize::new(pages.len() - pending.len());
+    let failures = Mutex::new(Vec::new());
+    let checkpoint = Mutex::new(checkpoint);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                while let Some(summary) = pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = Page::get_page_by_id(&config.api, &summary.id)
+                        .and_then(|page| write_page_file(config, &page, &dir, false));
+                    match result {
+                        Ok(path) => {
+                            checkpoint.lock().unwrap().complete(&summary.id).unwrap();
+                            let count = exported.fetch_add(1, Ordering::Relaxed) + 1;
+                            info!("[{}/{}] {}", count, pages.len(), path.display());
+                        }
+                        Err(e) => {
+                            let error = format!("{:#}", e);
+                            let mut checkpoint = checkpoint.lock().unwrap();
+                            checkpoint.fail(&summary.id, &error).unwrap();
+                            failures.lock().unwrap().push((summary, error));
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let failures = failures.into_inner().unwrap();
+    info!(
+        "Exported {} of {} pages from {} to {}",
+        pages.len() - failures.len(),
+        pages.len(),
+        space.key,
+        dir.display()
+    );
+    for (page, error) in &failures {
+        eprintln!("Failed to export {} ({}): {}", page.title, page.id, error);
+    }
+    checkpoint.into_inner().unwrap().finish().unwrap();
+    if !failures.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// Polls the space for pages created, updated or removed since the last poll,
+// comparing version numbers. The first poll only notes the pages there are.
+// Failed polls are reported and tried again at the next interval.
+pub fn watch_remote(config: &Config, space_key: &str, interval: Duration, exec: Option<&str>) {
+    let space = Space::get_space_by_key_or_id(&config.api, space_key).unwrap();
+    let mut known = remote_versions(config, &space).unwrap();
+    info!(
+        "Watching {} ({} pages) every {}s",
+        space.key,
+        known.len(),
+        interval.as_secs()
+    );
+    loop {
+        std::thread::sleep(interval);
+        let current = match remote_versions(config, &space) {
+            Ok(current) => current,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                continue;
+            }
+        };
+        for (id, (version, title)) in &current {
+            let event = match known.get(id) {
+                None => "created",
+                Some((known_version, _)) if known_version < version => "updated",
+                Some(_) => continue,
+            };
+            report_remote_change(config, &space, event, id, title, Some(*version), exec);
+        }
+        for (id, (_, title)) in known.iter().filter(|(id, _)| !current.contains_key(*id)) {
+            report_remote_change(config, &space, "removed", id, title, None, exec);
+        }
+        known = current;
+    }
+}
+
+// Finds the most recent update concmd made on this site in the audit log and
+// publishes the version before it as a new version, after showing what that
+// would change. Only the title and body are restored, not the parent or labels.
+pub fn undo_last_update(config: &Config) {
+    let entries = audit::read().unwrap();
+    // Each revert skips the latest update to its page before it, so undoing
+    // again goes further back rather than putting the update back
+    let mut reverted: Vec<&str> = Vec::new();
+    let last_update = entries
+        .iter()
+        .rev()
+        .filter(|entry| entry.site == config.api.confluence_domain)
+        .find(|entry| match entry.operation {
+            AuditOperation::Revert => {
+                reverted.push(&entry.id);
+                false
+            }
+            AuditOperation::Update => match reverted.iter().position(|id| *id == entry.id) {
+                Some(i) => {
+                    reverted.remove(i);
+                    false
+                }
+                None => entry.version.is_some_and(|version| version > 1),
+            },
+            _ => false,
+        });
+    let Some(entry) = last_update else {
+        info!("No page update by concmd to undo");
+        return;
+    };
+    let version = entry.version.expect("checked when finding the entry");
+    let mut page = Page::get_page_by_id(&config.api, &entry.id).unwrap();
+    let updated_since = page.version.number != version;
+    if updated_since {
+        println!(
+            "\"{}\" has been updated since concmd published version {} (now version {}), undoing will also throw those changes away",
+            page.title, version, page.version.number
+        );
+    }
+    let previous =
+        Page::get_page_at_version(&config.api, &entry.id, version - 1, page.body_format())
+            .unwrap();
+
+    let changes: Vec<(char, String)> = diff_blocks(
+        &convert::text_blocks(&page_to_markdown(config, &page).unwrap()),
+        &convert::text_blocks(&page_to_markdown(config, &previous).unwrap()),
+    )
+    .into_iter()
+    .filter(|(change, _)| *change != ' ')
+    .collect();
+    if changes.is_empty() && previous.title == page.title {
+        info!("\"{}\" already matches version {}", page.title, version - 1);
+        return;
+    }
+    println!(
+        "Undoing the update made at {} restores version {} of \"{}\":",
+        entry.timestamp,
+        version - 1,
+        page.title
+    );
+    if previous.title != page.title {
+        println!("  title: \"{}\" -> \"{}\"", page.title, previous.title);
+    }
+    // The diff is against the page as it is now, so it shows any later
+    // changes that would be lost too
+    for (change, block) in changes {
+        println!("  {} {}", change, block);
+    }
+    // Always asked when there are later changes, whatever confirm.publish says
+    let confirmed = match updated_since {
+        true => confirm(&format!(
+            "Throw away the changes made since version {} and restore version {}",
+            version,
+            version - 1
+        )),
+        false => !config.confirm.publish || confirm_publish(&page.title),
+    };
+    if !confirmed {
+        return;
+    }
+
+    page.set_body(previous.get_body().clone());
+    page.title = previous.title.clone();
+    page.version.message = Some(format!("Reverted to version {}", version - 1));
+    page.update_page_by_id(&config.api).unwrap();
+    audit::record(
+        config,
+        AuditOperation::Revert,
+        &page.id,
+        Some(page.version.number),
+        Some(&page.title),
+    );
+    info!(
+        "Restored version {} of \"{}\" as version {}",
+        version - 1,
+        page.title,
+        page.version.number
+    );
+}
+
+pub fn show_log(
+    page: Option<&str>,
+    operation: Option<&AuditOperation>,
+    limit: Option<usize>,
+    json: bool,
+) {
+    let mut entries: Vec<audit::Entry> = audit::read()
+        .unwrap()
+        .into_iter()
+        .filter(|entry| page.is_none_or(|id| entry.id == id))
+        .filter(|entry| operation.is_none_or(|operation| entry.operation == *operation))
+        .collect();
+    if let Some(limit) = limit {
+        entries.drain(..entries.len().saturating_sub(limit));
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+        return;
     }
+    for entry in entries {
+        let version = entry
+            .version
+            .map(|version| format!(" v{}", version))
+            .unwrap_or_default();
+        let title = entry
+            .title
+            .map(|title| format!(" \"{}\"", title))
+            .unwrap_or_default();
+        let detail = entry
+            .detail
+            .map(|detail| format!(" {}", detail))
+            .unwrap_or_default();
+        println!(
+            "{}  {:<9} {}{}{}{}  ({} on {})",
+            entry.timestamp,
+            entry.operation.to_string(),
+            entry.id,
+            version,
+            title,
+            detail,
+            entry.user,
+            entry.site
+        );
+    }
+}
+
+// Serves editor plugins until they close stdin. stdio is the only transport
+// for now, the flag is there so others can be added without breaking plugins.
+pub fn serve(config: &Config, stdio: bool) {
+    if !stdio {
+        eprintln!("No transport given, use --stdio");
+        std::process::exit(1);
+    }
+    // Progress messages on stdout would be mixed in with the responses
+    set_quiet(true);
+    let stdin = std::io::stdin();
+    serve::run(config, stdin.lock(), std::io::stdout()).unwrap();
+}
+
+// For commands that diagnose the setup, a missing or invalid config exits with
+// its own code and a message rather than a panic
+pub fn config_or_exit(config: Result<Config>) -> Config {
+    config.unwrap_or_else(|e| {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(EXIT_CONFIG_INVALID);
+    })
+}
+
+// Verifies the configured credentials with a cheap authenticated call. Exits
+// with a distinct code on failure so it can be used to check CI secrets.
+pub fn check_auth(config: &Config) {
+    let user = match User::get_current_user(&config.api) {
+        Ok(user) => user,
+        Err(e) => {
+            eprintln!("{}", e);
+            match e.downcast_ref::<ApiError>() {
+                Some(ApiError::Unauthorized { .. }) => std::process::exit(EXIT_AUTH_FAILED),
+                _ => std::process::exit(EXIT_REQUEST_FAILED),
+            }
+        }
+    };
+    // Confluence answers as an anonymous user rather than failing when no
+    // credentials are accepted on sites with public access
+    if user.user_type == "anonymous" {
+        eprintln!(
+            "Authentication failed for {} — check username/token",
+            config.api.confluence_domain
+        );
+        std::process::exit(EXIT_AUTH_FAILED);
+    }
+    info!(
+        "Authenticated to {} as {} ({})",
+        config.api.confluence_domain,
+        user.display_name,
+        user.email.as_deref().unwrap_or(&config.api.username)
+    );
+
+    match Space::get_spaces(&config.api) {
+        Ok(spaces) => info!("{} accessible spaces", spaces.len()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_REQUEST_FAILED);
+        }
+    }
+    info!(
+        "API tokens don't report their expiry date. Tokens expire at most a year after \
+        creation, check yours at https://id.atlassian.com/manage-profile/security/api-tokens"
+    );
 }
 
 // Worker functions
 
-fn save_page_to_file(location: &PathBuf, id: &String, body: &String) -> Result<PathBuf> {
-    let mut file_path = location.clone();
-    file_path.push(id);
+enum TitleResolution {
+    Create(String),
+    Existing(String),
+}
+
+// Works out what to do if a page with this title already exists in the space:
+// either the title to create the new page with, or the id of the existing page
+// to update instead
+fn resolve_title_conflict(
+    api: &Api,
+    space: &Space,
+    title: &str,
+    on_conflict: &OnConflict,
+) -> Result<TitleResolution> {
+    let existing = PageSummary::get_pages_by_title(api, Some(&space.id), title)?;
+    let Some(existing) = existing.first() else {
+        return Ok(TitleResolution::Create(title.to_string()));
+    };
+    match on_conflict {
+        OnConflict::Error => anyhow::bail!(
+            "A page titled \"{}\" already exists in {} ({})",
+            title,
+            space.name,
+            existing.id
+        ),
+        OnConflict::Suffix => {
+            for n in 2.. {
+                let suffixed = format!("{} ({})", title, n);
+                if PageSummary::get_pages_by_title(api, Some(&space.id), &suffixed)?.is_empty() {
+                    return Ok(TitleResolution::Create(suffixed));
+                }
+            }
+            unreachable!("a free suffix is always found")
+        }
+        OnConflict::Update => Ok(TitleResolution::Existing(existing.id.clone())),
+    }
+}
+
+fn print_operation_restrictions(operation: &str, restrictions: &OperationRestrictions) {
+    let users = restrictions.users();
+    let groups = restrictions.groups();
+    if users.is_empty() && groups.is_empty() {
+        println!("{}: unrestricted", operation);
+    } else {
+        println!(
+            "{}: users [{}], groups [{}]",
+            operation,
+            users.join(", "),
+            groups.join(", ")
+        );
+    }
+}
+
+// Finds the page at a path of titles like ENG/Guides/Onboarding. Titles are
+// unique within a space, so the last one finds the page and its ancestors have
+// to match the rest of the path, with or without the space's homepage.
+fn resolve_page_path(api: &Api, path: &str) -> Result<Option<String>> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (space_key, titles) = match segments.split_first() {
+        Some((space_key, titles)) if !titles.is_empty() => (*space_key, titles),
+        _ => anyhow::bail!("Expected a path like SPACE/Parent/Page, got \"{}\"", path),
+    };
+    let space = Space::get_space_by_key(api, space_key)?;
+    let (parents, title) = titles.split_at(titles.len() - 1);
+    let cql = format!(
+        "space = {} and type = page and title = {}",
+        conf_api::cql_string(&space.key),
+        conf_api::cql_string(title[0])
+    );
+    for candidate in Content::search(api, &cql)? {
+        let ancestors = conf_api::get_ancestors(api, &candidate.id)?;
+        let below_homepage = match ancestors.first() {
+            Some(top) if space.homepage_id.as_ref() == Some(&top.id) => &ancestors[1..],
+            _ => &ancestors[..],
+        };
+        let matches = |ancestors: &[Content]| {
+            ancestors.len() == parents.len()
+                && ancestors.iter().zip(parents).all(|(ancestor, title)| ancestor.title == *title)
+        };
+        if matches(&ancestors) || matches(below_homepage) {
+            return Ok(Some(candidate.id));
+        }
+    }
+    Ok(None)
+}
+
+// Looks up the space given on the command line, or asks the user to pick one
+// if none was given
+fn resolve_space(config: &Config, space: Option<&str>) -> Result<Space> {
+    let space = match space {
+        Some(key_or_id) => Space::get_space_by_key_or_id(&config.api, key_or_id)?,
+        None => choose_space(&config.api)?,
+    };
+    state::add_recent_space(&space.id)?;
+    Ok(space)
+}
+
+// Recent puts spaces that haven't been used at the end, by name
+fn sort_spaces(spaces: &mut [Space], sort: SpaceSort, recent: &[String]) {
+    match sort {
+        SpaceSort::Name => spaces.sort_by_key(|space| space.name.to_lowercase()),
+        SpaceSort::Key => spaces.sort_by(|a, b| a.key.cmp(&b.key)),
+        SpaceSort::Recent => spaces.sort_by_key(|space| {
+            let used = recent.iter().position(|id| id == &space.id);
+            (used.unwrap_or(usize::MAX), space.name.to_lowercase())
+        }),
+    }
+}
+
+fn choose_space(api: &Api) -> Result<Space> {
+    let state = State::load()?;
+    let mut spaces = Space::get_spaces(api)?;
+    sort_spaces(&mut spaces, state.space_sort, &state.recent_spaces);
+    for (i, space) in spaces.iter().enumerate() {
+        println!("  {}. {} ({})", i + 1, space.name, space.key);
+    }
+    loop {
+        print!("Select a space number:  ");
+        let selection: String = text_io::read!("{}\n");
+        if let Some(i) = selection.trim().parse::<usize>().ok().and_then(|n| n.checked_sub(1)) {
+            if i < spaces.len() {
+                return Ok(spaces.swap_remove(i));
+            }
+        }
+    }
+}
+
+// What became of a page after its editor closed
+enum PublishOutcome {
+    Published { version: usize },
+    Skipped,
+    Unchanged,
+    Cancelled,
+}
+
+impl std::fmt::Display for PublishOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PublishOutcome::Published { version } => write!(f, "published as version {}", version),
+            PublishOutcome::Skipped => write!(f, "not published"),
+            PublishOutcome::Unchanged => write!(f, "no changes, not published"),
+            PublishOutcome::Cancelled => write!(f, "editor exited with an error, not published"),
+        }
+    }
+}
+
+// Opens an already saved page in the editor and asks whether to publish it.
+// Either way the edit is no longer pending once the user has answered, or
+// once the editor exits with an error, which cancels the edit. saved is the
+// file as downloaded, if it hasn't been edited since.
+fn edit_saved_page(
+    config: &Config,
+    page: Page,
+    file_path: &PathBuf,
+    saved: Option<&str>,
+) -> Result<PublishOutcome> {
+    let title = page.title.clone();
+    let outcome = match open_editor(config, &[file_path])? {
+        true => publish_edited_page(config, page, file_path, saved)?,
+        false => {
+            state::remove_pending_edit(&page.id)?;
+            PublishOutcome::Cancelled
+        }
+    };
+    info!("{}: {}", title, outcome);
+    Ok(outcome)
+}
+
+// Checks whether the page has been updated on Confluence since page, the copy
+// the file was downloaded from, and if so asks before publishing over the
+// changes. Either way page is moved on to the latest version, so an update
+// made on top of it isn't rejected as a conflict.
+fn confirm_remote_unchanged(config: &Config, page: &mut Page) -> Result<bool> {
+    let remote_version = conf_api::get_page_version(&config.api, &page.id)?;
+    let confirmed = remote_version == page.version.number || {
+        println!(
+            "{} has been updated on Confluence since it was downloaded (version {}, now {})",
+            page.title, page.version.number, remote_version
+        );
+        !config.confirm.overwrite || confirm_publish(&page.title)
+    };
+    page.version.number = remote_version;
+    Ok(confirmed)
+}
+
+// Publishes a page after editing as set by auto_sync. Pages whose file still
+// matches saved are left alone, and an emptied page is only published once
+// the user confirms it, whatever auto_sync says, unless confirm.overwrite is
+// turned off.
+fn publish_edited_page(
+    config: &Config,
+    mut page: Page,
+    file_path: &PathBuf,
+    saved: Option<&str>,
+) -> Result<PublishOutcome> {
+    if saved.is_some_and(|saved| crypt::read_to_string(file_path).is_ok_and(|now| now == saved)) {
+        state::remove_pending_edit(&page.id)?;
+        if config.save_mode == SaveMode::Temp {
+            remove_saved_file(config, file_path)?;
+        }
+        return Ok(PublishOutcome::Unchanged);
+    }
+    let markdown = crypt::read_to_string(file_path)?;
+    let (frontmatter, body) = frontmatter::split(&markdown)?;
+    print_lint_warnings(file_path, &markdown, body);
+    let changes = MetadataChanges::between(&config.api, &page, frontmatter.as_ref())?;
+    if !changes.is_empty() {
+        print!("Publishing {} will also change:\n{}", page.title, changes);
+    }
+    let publish = match config.auto_sync {
+        AutoSync::Never => {
+            info!("Changes kept in {}", file_path.display());
+            false
+        }
+        _ if body.trim().is_empty() && config.confirm.overwrite => {
+            confirm_blank_page(&page.title, file_path)
+        }
+        AutoSync::Always | AutoSync::IfUnchangedRemote => true,
+        AutoSync::Prompt => !config.confirm.publish || confirm_publish(&page.title),
+    };
+    // The version is checked again just before publishing, however long the
+    // editor was open, and changes made on Confluence are only published over
+    // once confirmed, whatever auto_sync says.
+    let publish = publish && confirm_remote_unchanged(config, &mut page)?;
+    let outcome = match publish {
+        true => PublishOutcome::Published {
+            version: upload_page_by_id(config, &mut page, file_path, &changes)?,
+        },
+        false => PublishOutcome::Skipped,
+    };
+    state::remove_pending_edit(&page.id)?;
+    Ok(outcome)
+}
+
+// Downloads the page again and diffs the text of each block against the
+// markdown it was published from, ignoring formatting changes from the round
+// trip. Returns whether nothing was lost.
+fn verify_published_page(config: &Config, id: &str, written: &str) -> Result<bool> {
+    let page = Page::get_page_by_id(&config.api, id)?;
+    let published = page_to_markdown(config, &page)?;
+    let changes: Vec<(char, String)> = diff_blocks(
+        &convert::text_blocks(written),
+        &convert::text_blocks(&published),
+    )
+    .into_iter()
+    .filter(|(change, _)| *change != ' ')
+    .collect();
+    if changes.is_empty() {
+        info!("Verified \"{}\": no content lost in conversion", page.title);
+        return Ok(true);
+    }
+    println!(
+        "\"{}\" differs from what was written (- lost, + added by conversion):",
+        page.title
+    );
+    for (change, block) in changes {
+        println!("  {} {}", change, block);
+    }
+    Ok(false)
+}
+
+// A line diff from the longest common subsequence, with each block marked ' '
+// if it is in both, '-' if only in the old blocks and '+' if only in the new
+fn diff_blocks(old: &[String], new: &[String]) -> Vec<(char, String)> {
+    // lengths[i][j] is the length of the common subsequence of old[i..] and
+    // new[j..]
+    let mut lengths = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = match old[i] == new[j] {
+                true => lengths[i + 1][j + 1] + 1,
+                false => lengths[i + 1][j].max(lengths[i][j + 1]),
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::new();
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            diff.push((' ', old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            diff.push(('-', old[i].clone()));
+            i += 1;
+        } else {
+            diff.push(('+', new[j].clone()));
+            j += 1;
+        }
+    }
+    diff
+}
+
+// Differences between a saved file's frontmatter and the page on Confluence,
+// applied along with the body when the file is uploaded. Anything left out of
+// the frontmatter is left as it is.
+#[derive(Default)]
+struct MetadataChanges {
+    title: Option<(String, String)>,
+    parent: Option<(Option<String>, String)>,
+    add_labels: Vec<String>,
+    remove_labels: Vec<String>,
+}
+
+impl MetadataChanges {
+    fn between(
+        api: &Api,
+        page: &Page,
+        frontmatter: Option<&Frontmatter>,
+    ) -> Result<MetadataChanges> {
+        let Some(frontmatter) = frontmatter else {
+            return Ok(MetadataChanges::default());
+        };
+        let mut changes = MetadataChanges::default();
+        if let Some(title) = frontmatter
+            .title
+            .as_ref()
+            .filter(|title| **title != page.title)
+        {
+            changes.title = Some((page.title.clone(), title.clone()));
+        }
+        if let Some(parent) = frontmatter
+            .parent
+            .as_ref()
+            .filter(|parent| page.parent_id.as_ref() != Some(*parent))
+        {
+            changes.parent = Some((page.parent_id.clone(), parent.clone()));
+        }
+        if let Some(labels) = &frontmatter.labels {
+            let remote = page.get_labels(api)?;
+            changes.add_labels = labels
+                .iter()
+                .filter(|label| !remote.contains(label))
+                .cloned()
+                .collect();
+            changes.remove_labels = remote
+                .into_iter()
+                .filter(|label| !labels.contains(label))
+                .collect();
+        }
+        Ok(changes)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.parent.is_none()
+            && self.add_labels.is_empty()
+            && self.remove_labels.is_empty()
+    }
+
+    // The title and parent are sent with the body in the page update
+    fn apply_to_page(&self, page: &mut Page) {
+        if let Some((_, title)) = &self.title {
+            page.title = title.clone();
+        }
+        if let Some((_, parent)) = &self.parent {
+            page.parent_id = Some(parent.clone());
+        }
+    }
+
+    fn update_labels(&self, config: &Config, id: &str) -> Result<()> {
+        for label in &self.add_labels {
+            conf_api::add_label(&config.api, id, label)?;
+            audit::record_detail(config, AuditOperation::Label, id, label);
+        }
+        for label in &self.remove_labels {
+            conf_api::remove_label(&config.api, id, label)?;
+            audit::record_detail(config, AuditOperation::Unlabel, id, label);
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for MetadataChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some((old, new)) = &self.title {
+            writeln!(f, "  title: \"{}\" -> \"{}\"", old, new)?;
+        }
+        if let Some((old, new)) = &self.parent {
+            writeln!(
+                f,
+                "  parent: {} -> {}",
+                old.as_deref().unwrap_or("none"),
+                new
+            )?;
+        }
+        for label in &self.add_labels {
+            writeln!(f, "  + label {}", label)?;
+        }
+        for label in &self.remove_labels {
+            writeln!(f, "  - label {}", label)?;
+        }
+        Ok(())
+    }
+}
+
+// Prints any constructs in the body that won't convert cleanly, numbering
+// lines from the top of the file rather than the end of the frontmatter.
+// Returns whether the body is clean.
+fn print_lint_warnings(path: &Path, markdown: &str, body: &str) -> bool {
+    let first_line = markdown[..markdown.len() - body.len()].matches('\n').count() + 1;
+    let warnings = lint::lint(body, first_line);
+    for warning in &warnings {
+        eprintln!("Warning: {}: {}", path.display(), warning);
+    }
+    warnings.is_empty()
+}
+
+fn confirm_publish(title: &str) -> bool {
+    confirm(&format!("Do you wish to publish {}", title))
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{}: y/n?  ", question);
+
+    let user_input: String = text_io::read!("{}\n");
+    matches!(user_input.as_str(), "y" | "Y" | "yes" | "Yes")
+}
+
+fn confirm_blank_page(title: &str, file_path: &Path) -> bool {
+    println!(
+        "Warning: {} is empty, publishing it will blank {}",
+        file_path.display(),
+        title
+    );
+    confirm_publish(title)
+}
+
+fn replace_matches(pattern: &Regex, text: &str, replacement: &str, is_regex: bool) -> String {
+    match is_regex {
+        true => pattern.replace_all(text, replacement).into_owned(),
+        false => pattern
+            .replace_all(text, regex::NoExpand(replacement))
+            .into_owned(),
+    }
+}
+
+// In ADF only the text of text nodes and the targets of links are replaced,
+// leaving node types, attributes and the json itself as they were
+fn replace_in_body(
+    body: &str,
+    format: BodyFormat,
+    pattern: &Regex,
+    replacement: &str,
+    is_regex: bool,
+) -> Result<String> {
+    fn replace_text(value: &mut serde_json::Value, replace: &dyn Fn(&str) -> String) {
+        match value {
+            serde_json::Value::Array(values) => {
+                values.iter_mut().for_each(|value| replace_text(value, replace))
+            }
+            serde_json::Value::Object(map) => {
+                let field = match map.get("type").and_then(|node_type| node_type.as_str()) {
+                    Some("text") => map.get_mut("text"),
+                    Some("link") => map.get_mut("attrs").and_then(|attrs| attrs.get_mut("href")),
+                    _ => None,
+                };
+                if let Some(serde_json::Value::String(text)) = field {
+                    *text = replace(text);
+                }
+                // Marks, including links, sit inside the text nodes
+                map.values_mut()
+                    .for_each(|value| replace_text(value, replace));
+            }
+            _ => (),
+        }
+    }
+
+    match format {
+        BodyFormat::Storage => Ok(replace_matches(pattern, body, replacement, is_regex)),
+        BodyFormat::Adf => {
+            let mut doc: serde_json::Value = serde_json::from_str(body)?;
+            let original = doc.clone();
+            replace_text(&mut doc, &|text| {
+                replace_matches(pattern, text, replacement, is_regex)
+            });
+            // Serialising may reorder keys, so an unmatched body is returned
+            // as it was
+            match doc == original {
+                true => Ok(body.to_string()),
+                false => Ok(doc.to_string()),
+            }
+        }
+    }
+}
+
+// Prints each match with a little of the text around it, before and after
+// replacing
+fn print_replacements(body: &str, pattern: &Regex, replacement: &str, is_regex: bool) {
+    const CONTEXT: usize = 30;
+    for captures in pattern.captures_iter(body) {
+        let found = captures.get(0).expect("group 0 is always the whole match");
+        let mut replaced = String::new();
+        match is_regex {
+            true => captures.expand(replacement, &mut replaced),
+            false => replaced.push_str(replacement),
+        }
+        let before = take_width(body[..found.start()].chars().rev(), CONTEXT);
+        let before: String = before.chars().rev().collect();
+        let after = take_width(body[found.end()..].chars(), CONTEXT);
+        println!("  - …{}{}{}…", before, found.as_str(), after);
+        println!("  + …{}{}{}…", before, replaced, after);
+    }
+}
+
+// The leading chars that fit in width terminal columns, with newlines as
+// spaces. Wide characters such as CJK take two columns.
+fn take_width(chars: impl Iterator<Item = char>, width: usize) -> String {
+    let mut used = 0;
+    let mut taken = String::new();
+    for c in chars.map(|c| if c == '\n' { ' ' } else { c }) {
+        used += c.width().unwrap_or(0);
+        if used > width {
+            break;
+        }
+        taken.push(c);
+    }
+    taken
+}
+
+// Cuts text to width terminal columns, ending it with … if anything was cut
+fn truncate_to_width(text: &str, width: usize) -> String {
+    let taken = take_width(text.chars(), width);
+    if taken.chars().count() == text.chars().count() {
+        return taken;
+    }
+    let mut truncated = take_width(text.chars(), width.saturating_sub(1));
+    truncated.push('…');
+    truncated
+}
+
+// Column widths for the lists that line their columns up, which leave the rest
+// of the line to the title
+const ID_COLUMN: usize = 12;
+const KEY_COLUMN: usize = 12;
+const DATE_COLUMN: usize = 10;
+
+// Fits a title into what is left of the terminal line after other_columns.
+// Output that isn't going to a terminal is left whole for scripts.
+fn fit_title(title: &str, other_columns: usize) -> String {
+    const MIN_TITLE_WIDTH: usize = 20;
+    if !std::io::stdout().is_terminal() {
+        return title.to_string();
+    }
+    let (width, _) = termimad::terminal_size();
+    let width = (width as usize)
+        .saturating_sub(other_columns)
+        .max(MIN_TITLE_WIDTH);
+    truncate_to_width(title, width)
+}
+
+fn last_updated(page: &PageSummary) -> Option<SystemTime> {
+    parse_timestamp(page.version.as_ref()?.created_at.as_deref()?)
+}
+
+// Parses the UTC timestamps Confluence returns, e.g. 2024-01-31T09:30:00.000Z
+fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| timestamp.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    // Days since the epoch from a civil date, from Howard Hinnant's date
+    // algorithms
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let seconds = days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60 + second;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+// On Linux the clipboard is only served while the program that set it is
+// running, so a detached concmd hold-clipboard keeps it after this one exits
+#[cfg(target_os = "linux")]
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("hold-clipboard")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(text.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    arboard::Clipboard::new()?.set_text(text)?;
+    Ok(())
+}
+
+fn archived_marker(page: &PageSummary) -> &str {
+    match page.status.as_str() {
+        "archived" => " [archived]",
+        _ => "",
+    }
+}
+
+fn print_content_tree(api: &Api, content: &Content, depth: usize) -> Result<()> {
+    println!(
+        "{}{} {} ({})",
+        "  ".repeat(depth),
+        content.content_type.icon(),
+        content.title,
+        content.id
+    );
+    for child in content.get_direct_children(api)? {
+        print_content_tree(api, &child, depth + 1)?;
+    }
+    Ok(())
+}
+
+// Catches renames Confluence would reject before any of the plan is run,
+// rather than failing part way through: empty titles, titles already used by
+// another page in the space, and titles an earlier create or rename in the
+// plan takes
+fn check_renames(api: &Api, space: &Space, operations: &[Operation]) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    // Title to the page renamed to it, or None for a page the plan creates
+    let mut claimed: HashMap<&str, Option<&str>> = HashMap::new();
+    for operation in operations {
+        let (id, title) = match operation {
+            Operation::Create { title, .. } => {
+                claimed.entry(title).or_insert(None);
+                continue;
+            }
+            Operation::Rename { id, title } => (id, title),
+            _ => continue,
+        };
+        if title.trim().is_empty() {
+            problems.push(format!("Page {} can't be renamed to an empty title", id));
+            continue;
+        }
+        match claimed.get(title.as_str()) {
+            Some(None) => {
+                problems.push(format!(
+                    "Page {} can't be renamed to \"{}\", the plan creates a page with that title first",
+                    id, title
+                ));
+                continue;
+            }
+            Some(Some(other)) if other != id => {
+                problems.push(format!(
+                    "Page {} can't be renamed to \"{}\", the plan renames page {} to it first",
+                    id, title, other
+                ));
+                continue;
+            }
+            _ => {
+                claimed.insert(title, Some(id));
+            }
+        }
+        let existing = PageSummary::get_pages_by_title(api, Some(&space.id), title)?;
+        if let Some(other) = existing.iter().find(|page| &page.id != id) {
+            problems.push(format!(
+                "Page {} can't be renamed to \"{}\", page {} already has that title",
+                id, title, other.id
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+fn run_operation(config: &Config, space: &Space, operation: &Operation) -> Result<()> {
+    let api = &config.api;
+    match operation {
+        Operation::Create {
+            title,
+            parent_id,
+            body,
+        } => {
+            let id = conf_api::create_page(api, &space.id, title, parent_id.as_deref(), body)?;
+            audit::record(config, AuditOperation::Create, &id, Some(1), Some(title));
+            info!("Created page {}", id);
+        }
+        Operation::Rename { id, title } => {
+            conf_api::rename_page(api, id, title)?;
+            audit::record(config, AuditOperation::Rename, id, None, Some(title));
+        }
+        Operation::AddLabel { id, label } => {
+            conf_api::add_label(api, id, label)?;
+            audit::record_detail(config, AuditOperation::Label, id, label);
+        }
+        Operation::Move { id, parent_id } => {
+            conf_api::move_page(api, id, parent_id)?;
+            audit::record(config, AuditOperation::Move, id, None, None);
+        }
+        Operation::Delete { id } => {
+            conf_api::delete_page(api, id)?;
+            audit::record(config, AuditOperation::Delete, id, None, None);
+        }
+    }
+    Ok(())
+}
+
+fn record_update(config: &Config, page: &Page) {
+    audit::record(
+        config,
+        AuditOperation::Update,
+        &page.id,
+        Some(page.version.number),
+        Some(&page.title),
+    );
+}
+
+// Saves the page as markdown, with frontmatter describing the page at the top
+fn save_page_to_file(config: &Config, page: &Page) -> Result<PathBuf> {
+    let dir = match config.save_mode {
+        SaveMode::Directory => config.save_location.clone(),
+        SaveMode::Temp => temp_session_dir()?,
+    };
+    write_page_file(config, page, &dir, config.encrypt_files)
+}
+
+fn write_page_file(config: &Config, page: &Page, dir: &Path, encrypted: bool) -> Result<PathBuf> {
+    let mut file_path = dir.join(page_file_name(config, page));
     file_path.set_extension("md");
-    let mut file = File::create(&file_path)?;
     // let body_unescaped = unescape_chars(body);
     // let body_table_replaced = remove_complex_table(&body_unescaped);
-    let body_table_replaced = html2md::parse_html(body);
-    file.write_all(body_table_replaced.as_bytes())?;
+    let body_table_replaced = page_to_markdown(config, page)?;
+    let frontmatter = Frontmatter {
+        id: Some(page.id.clone()),
+        title: Some(page.title.clone()),
+        space: Some(Space::get_space_by_id(&config.api, &page.space_id)?.key),
+        version: Some(page.version.number),
+        labels: Some(page.get_labels(&config.api)?),
+        parent: page.parent_id.clone(),
+    };
+    let markdown = frontmatter::join(&frontmatter, &body_table_replaced)?;
+    crypt::write(&file_path, &markdown, encrypted)?;
     Ok(file_path)
 }
 
+// Named on first use so no other run can have guessed it, and only readable by
+// the user. Created again if it was removed after publishing an earlier page.
+fn temp_session_dir() -> Result<PathBuf> {
+    static SESSION_DIR: OnceLock<PathBuf> = OnceLock::new();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .subsec_nanos();
+    let dir = SESSION_DIR.get_or_init(|| {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("concmd-{}-{}", std::process::id(), nanos));
+        dir
+    });
+    if !dir.exists() {
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        // Fails rather than reusing a directory someone else created
+        builder.create(dir)?;
+    }
+    Ok(dir.clone())
+}
+
+// Removes a saved page, and in temp mode the run's directory once it is empty
+fn remove_saved_file(config: &Config, file_path: &Path) -> Result<()> {
+    std::fs::remove_file(file_path)?;
+    if config.save_mode == SaveMode::Temp {
+        if let Some(dir) = file_path.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+    Ok(())
+}
+
 // fn custom_tables(ele: Element) -> Option<String> {
 //     match ele.node.children.clone().into_inner().iter().nth(0) {
 //         Some(header_row) => generate_table_row(header_row),
@@ -72,29 +2841,542 @@ fn save_page_to_file(location: &PathBuf, id: &String, body: &String) -> Result<P
 //         .replace("&ldquo;", "\"")
 // }
 
-fn reescape_chars(body: &String) -> String {
-    body.replace("\"", "&quot;")
-        .replace("'", "&rsquo;")
-        .replace("'", "&lsquo;")
-        .replace("\"", "&rdquo;")
-        .replace("\"", "&ldquo;")
-}
-
-fn open_editor(path: &PathBuf) {
-    let _ = Command::new("nvim")
-        .arg(path)
-        .spawn()
-        .expect("failed to open nvim")
-        .wait()
-        .expect("nvim exited with non-zero status");
-}
-
-fn upload_page_by_id(api: &Api, page: &mut Page, file_path: &PathBuf) -> Result<()> {
-    let mut file = File::open(file_path)?;
-    let mut unescaped_body = String::new();
-    file.read_to_string(&mut unescaped_body)?;
-    page.set_body(reescape_chars(&unescaped_body));
-    // Process here if needed
-    page.update_page_by_id(api)?;
+// File name (without extension) for a saved page, from the configured pattern
+fn page_file_name(config: &Config, page: &Page) -> String {
+    config
+        .filename_pattern
+        .replace("{slug}", &slugify(&page.title))
+        .replace("{id}", &page.id)
+}
+
+// Lowercase alphanumerics separated by single dashes, e.g. "Q3 Plans: Draft"
+// becomes "q3-plans-draft"
+fn slugify(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// Finds the saved file for a page id in the save location, whether it was
+// saved as <id>.md or with a title slug like <slug>-<id>.md
+fn find_saved_file(config: &Config, id: &str) -> Option<PathBuf> {
+    let suffix = format!("-{}.md", id);
+    let plain = format!("{}.md", id);
+    std::fs::read_dir(&config.save_location)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name == plain || name.ends_with(&suffix)
+        })
+}
+
+// Cuts converted markdown down to the preview length. Character limits back
+// off to the last word boundary so words and markup delimiters aren't split,
+// and a code block left open by the cut is closed again so the rest of the
+// preview doesn't render as code.
+fn truncate_markdown(markdown: &str, preview: &PreviewLength) -> String {
+    let markdown = markdown.trim_end();
+    let mut truncated = match preview {
+        PreviewLength::Full => return markdown.to_string(),
+        PreviewLength::Lines(lines) => markdown
+            .lines()
+            .take(*lines)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        PreviewLength::Chars(chars) => match markdown.char_indices().nth(*chars) {
+            None => markdown.to_string(),
+            Some((end, c)) => {
+                let cut = &markdown[..end];
+                // Only back off if the cut landed inside a word
+                let cut = if c.is_whitespace() {
+                    cut
+                } else {
+                    cut.rfind(char::is_whitespace).map_or(cut, |i| &cut[..i])
+                };
+                cut.trim_end().to_string()
+            }
+        },
+    };
+    if truncated.len() == markdown.len() {
+        return truncated;
+    }
+    let mut open_fence: Option<&str> = None;
+    for line in truncated.lines() {
+        let line = line.trim_start();
+        let fence = ["```", "~~~"]
+            .into_iter()
+            .find(|fence| line.starts_with(fence));
+        match (open_fence, fence) {
+            (None, Some(fence)) => open_fence = Some(fence),
+            (Some(open), Some(fence)) if open == fence => open_fence = None,
+            _ => (),
+        }
+    }
+    if let Some(fence) = open_fence {
+        truncated = format!("{}\n{}", truncated, fence);
+    }
+    truncated.push_str("\n…\n");
+    truncated
+}
+
+// The page body as markdown, with user mentions as readable @names
+fn page_to_markdown(config: &Config, page: &Page) -> Result<String> {
+    let markdown = convert::body_to_markdown(page.get_body(), page.body_format(), Some(config))?;
+    mentions::mentions_to_names(&config.api, &markdown)
+}
+
+// Converts markdown to a body for the page, looking up the users behind any
+// @names
+fn markdown_to_page_body(config: &Config, page: &Page, markdown: &str) -> Result<String> {
+    let markdown = mentions::names_to_mentions(&config.api, markdown, page.body_format())?;
+    convert::markdown_to_body(&markdown, page.body_format(), Some(config))
+}
+
+// Styles the markdown for the terminal and pipes it into $PAGER, falling back
+// to less
+fn show_in_pager(markdown: &str) -> Result<()> {
+    let (width, _) = termimad::terminal_size();
+    let rendered = termimad::MadSkin::default()
+        .text(markdown, Some(width as usize))
+        .to_string();
+
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
+    let mut pager_args = pager.split_whitespace();
+    let mut child = Command::new(pager_args.next().unwrap_or("less"))
+        .args(pager_args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager closes its input if the user quits before reading to the
+        // end, which isn't an error
+        match stdin.write_all(rendered.as_bytes()) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+            result => result?,
+        }
+    }
+    child.wait()?;
     Ok(())
 }
+
+// Several files are opened as tabs in the same session. Returns false when
+// the editor exits with an error, e.g. after :cq, so the edit can be cancelled.
+// Encrypted files are only ever decrypted into a copy outside save_location.
+fn open_editor<P: AsRef<Path>>(config: &Config, paths: &[P]) -> Result<bool> {
+    if !config.encrypt_files {
+        // Files saved while encryption was on are left decrypted from now on
+        for path in paths {
+            crypt::write(path, &crypt::read_to_string(path)?, false)?;
+        }
+        let status = Command::new("nvim")
+            .arg("-p")
+            .args(paths.iter().map(|path| path.as_ref()))
+            .status();
+        return Ok(status.context("failed to open nvim")?.success());
+    }
+    // Encrypted pages are edited as decrypted copies in the private temp
+    // directory rather than decrypted in place, so no plain text is left in
+    // save_location if concmd is stopped while the editor is open
+    let copies = paths
+        .iter()
+        .map(|path| DecryptedCopy::create(path.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    let status = Command::new("nvim")
+        .arg("-p")
+        .args(copies.iter().map(|copy| &copy.path))
+        .status();
+    for copy in &copies {
+        copy.encrypt_back()?;
+    }
+    Ok(status.context("failed to open nvim")?.success())
+}
+
+// The decrypted copies open in the editor, as (copy, saved file) pairs, for
+// the Ctrl-C handler to put back
+static DECRYPTED_COPIES: Mutex<Vec<(PathBuf, PathBuf)>> = Mutex::new(Vec::new());
+
+// A decrypted copy of an encrypted saved page, only readable by the user and
+// removed when dropped, including when concmd panics
+struct DecryptedCopy {
+    path: PathBuf,
+    saved: PathBuf,
+}
+
+impl DecryptedCopy {
+    fn create(saved: &Path) -> Result<DecryptedCopy> {
+        let file_name = saved
+            .file_name()
+            .context("The saved page has no file name")?;
+        let path = temp_session_dir()?.join(format!("editing-{}", file_name.to_string_lossy()));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&path)
+            .with_context(|| format!("{} could not be created", path.display()))?;
+        let copy = DecryptedCopy {
+            path,
+            saved: saved.to_path_buf(),
+        };
+        lock_decrypted_copies().push((copy.path.clone(), copy.saved.clone()));
+        file.write_all(crypt::read_to_string(saved)?.as_bytes())?;
+        Ok(copy)
+    }
+
+    fn encrypt_back(&self) -> Result<()> {
+        crypt::write(&self.saved, &crypt::read_to_string(&self.path)?, true)
+    }
+}
+
+impl Drop for DecryptedCopy {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+        // Only removed once empty, so saved pages in temp mode are kept
+        if let Some(dir) = self.path.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
+        lock_decrypted_copies().retain(|(path, _)| *path != self.path);
+    }
+}
+
+// A panic while the lock was held still leaves the list usable
+fn lock_decrypted_copies() -> std::sync::MutexGuard<'static, Vec<(PathBuf, PathBuf)>> {
+    DECRYPTED_COPIES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Encrypts whatever has been written to the open copies back into the saved
+// files and removes the copies, for when concmd exits without returning
+fn close_decrypted_copies() {
+    for (path, saved) in lock_decrypted_copies().drain(..) {
+        if let Ok(markdown) = crypt::read_to_string(&path) {
+            let _ = crypt::write(&saved, &markdown, true);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+// Returns the version number the page was published as
+fn upload_page_by_id(
+    config: &Config,
+    page: &mut Page,
+    file_path: &PathBuf,
+    changes: &MetadataChanges,
+) -> Result<usize> {
+    let markdown = crypt::read_to_string(file_path)?;
+    let (_, body) = frontmatter::split(&markdown)?;
+    page.set_body(markdown_to_page_body(config, page, body)?);
+    changes.apply_to_page(page);
+    page.update_page_by_id(&config.api)?;
+    record_update(config, page);
+    changes.update_labels(config, &page.id)?;
+    if config.auto_clean || config.save_mode == SaveMode::Temp {
+        remove_saved_file(config, file_path)?;
+    }
+    Ok(page.version.number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn done_exits_successfully() {
+        assert_eq!(exit_code(&Ok(CommandOutcome::Done)), None);
+    }
+
+    #[test]
+    fn cancelled_and_failed_outcomes_have_their_own_codes() {
+        assert_eq!(
+            exit_code(&Ok(CommandOutcome::Cancelled)),
+            Some(EXIT_USER_CANCEL)
+        );
+        assert_eq!(
+            exit_code(&Ok(CommandOutcome::Failed("no page".to_string()))),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn confluence_errors_get_the_check_auth_codes() {
+        let unauthorized = ApiError::Unauthorized {
+            domain: "example.atlassian.net".to_string(),
+        };
+        let rate_limited = ApiError::RateLimited {
+            domain: "example.atlassian.net".to_string(),
+        };
+        assert_eq!(exit_code(&Err(unauthorized.into())), Some(EXIT_AUTH_FAILED));
+        assert_eq!(
+            exit_code(&Err(rate_limited.into())),
+            Some(EXIT_REQUEST_FAILED)
+        );
+    }
+
+    #[test]
+    fn other_errors_exit_with_one() {
+        assert_eq!(exit_code(&Err(anyhow::anyhow!("no editor"))), Some(1));
+    }
+
+    fn blocks(blocks: &[&str]) -> Vec<String> {
+        blocks.iter().map(|block| block.to_string()).collect()
+    }
+
+    #[test]
+    fn slugs_keep_only_words() {
+        assert_eq!(slugify("Q3 Plans: Draft"), "q3-plans-draft");
+        assert_eq!(slugify("  --Release -- notes--  "), "release-notes");
+        assert_eq!(slugify("Über Café"), "über-café");
+        assert_eq!(slugify("日本語 ページ"), "日本語-ページ");
+        assert_eq!(slugify("!?"), "");
+        assert_eq!(slugify(""), "");
+    }
+
+    #[test]
+    fn diff_marks_removed_blocks_before_added_ones() {
+        let diff = diff_blocks(&blocks(&["a", "b", "c"]), &blocks(&["a", "x", "c"]));
+        assert_eq!(
+            diff,
+            vec![
+                (' ', "a".to_string()),
+                ('-', "b".to_string()),
+                ('+', "x".to_string()),
+                (' ', "c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_of_empty_sides() {
+        assert!(diff_blocks(&[], &[]).is_empty());
+        assert_eq!(
+            diff_blocks(&[], &blocks(&["a"])),
+            vec![('+', "a".to_string())]
+        );
+        assert_eq!(
+            diff_blocks(&blocks(&["a"]), &[]),
+            vec![('-', "a".to_string())]
+        );
+    }
+
+    #[test]
+    fn diff_keeps_repeated_blocks() {
+        let diff = diff_blocks(&blocks(&["a", "a"]), &blocks(&["a", "a", "a"]));
+        assert_eq!(diff.iter().filter(|(change, _)| *change == ' ').count(), 2);
+        assert_eq!(diff.iter().filter(|(change, _)| *change == '+').count(), 1);
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        let at = |seconds| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds));
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00.000Z"), at(0));
+        assert_eq!(parse_timestamp("2024-01-31T09:30:00.000Z"), at(1706693400));
+        assert_eq!(parse_timestamp("2024-02-29T00:00:00.000Z"), at(1709164800));
+        assert_eq!(parse_timestamp("2000-03-01T12:00:00Z"), at(951912000));
+    }
+
+    #[test]
+    fn bad_timestamps_are_none() {
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("2024-01-31"), None);
+        assert_eq!(parse_timestamp("2024-01-31T09:3"), None);
+        assert_eq!(parse_timestamp("yesterday at noon!!"), None);
+        // Before the epoch doesn't fit in a SystemTime offset
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59.000Z"), None);
+    }
+
+    #[test]
+    fn truncating_to_width_counts_wide_characters_twice() {
+        assert_eq!(truncate_to_width("hello", 5), "hello");
+        assert_eq!(truncate_to_width("hello world", 5), "hell…");
+        assert_eq!(truncate_to_width("日本語", 6), "日本語");
+        assert_eq!(truncate_to_width("日本語テキスト", 6), "日本…");
+        // A wide character that doesn't fit leaves the column empty
+        assert_eq!(truncate_to_width("日本語テキスト", 5), "日本…");
+        assert_eq!(truncate_to_width("", 5), "");
+    }
+
+    #[test]
+    fn markdown_shorter_than_the_preview_is_left_whole() {
+        let markdown = "# Title\n\nSome text\n\n";
+        assert_eq!(
+            truncate_markdown(markdown, &PreviewLength::Full),
+            "# Title\n\nSome text"
+        );
+        assert_eq!(
+            truncate_markdown(markdown, &PreviewLength::Lines(3)),
+            "# Title\n\nSome text"
+        );
+        assert_eq!(
+            truncate_markdown(markdown, &PreviewLength::Chars(100)),
+            "# Title\n\nSome text"
+        );
+    }
+
+    #[test]
+    fn markdown_is_cut_at_lines_and_word_boundaries() {
+        assert_eq!(
+            truncate_markdown("a\nb\nc", &PreviewLength::Lines(2)),
+            "a\nb\n…\n"
+        );
+        assert_eq!(
+            truncate_markdown("hello world", &PreviewLength::Chars(8)),
+            "hello\n…\n"
+        );
+        assert_eq!(
+            truncate_markdown("hello world", &PreviewLength::Chars(6)),
+            "hello\n…\n"
+        );
+        assert_eq!(
+            truncate_markdown("日本語のテキスト", &PreviewLength::Chars(3)),
+            "日本語\n…\n"
+        );
+    }
+
+    #[test]
+    fn cut_code_blocks_are_closed() {
+        let markdown = "```rust\nfn main() {}\n```\n\n~~~\none\ntwo\n~~~";
+        assert_eq!(
+            truncate_markdown(markdown, &PreviewLength::Lines(6)),
+            "```rust\nfn main() {}\n```\n\n~~~\none\n~~~\n…\n"
+        );
+        assert_eq!(
+            truncate_markdown(markdown, &PreviewLength::Lines(3)),
+            "```rust\nfn main() {}\n```\n…\n"
+        );
+    }
+
+    #[test]
+    fn title_templates_fill_in_the_file_and_heading() {
+        let markdown = "Intro\n\n## Not this\n\n# Road `map`\n";
+        let path = Path::new("notes/plan.md");
+        assert_eq!(
+            render_title_template("{filename} — {h1}", Some(path), markdown),
+            "plan — Road map"
+        );
+        // Nothing to fill in from stdin or a file without a heading
+        assert_eq!(
+            render_title_template("{filename} {h1}", Some(Path::new("-")), ""),
+            ""
+        );
+        assert_eq!(render_title_template("  Notes  ", None, ""), "Notes");
+        let dated = render_title_template("{date}", None, "");
+        assert_eq!(dated.len(), 10);
+        assert_eq!(dated.matches('-').count(), 2);
+    }
+
+    fn test_api() -> Api {
+        toml::from_str(
+            "confluence_domain = 'example.invalid'\n\
+             username = 'user@example.com'\n\
+             token = 'token'\n",
+        )
+        .expect("the test api should parse")
+    }
+
+    fn test_space() -> Space {
+        Space {
+            id: "1".to_string(),
+            key: "ENG".to_string(),
+            name: "Engineering".to_string(),
+            homepage_id: None,
+        }
+    }
+
+    // None of these renames get as far as asking Confluence whether the title
+    // is taken
+    #[test]
+    fn renames_to_empty_or_planned_titles_are_caught() {
+        let rename = |id: &str, title: &str| Operation::Rename {
+            id: id.to_string(),
+            title: title.to_string(),
+        };
+        let operations = vec![
+            rename("10", ""),
+            rename("11", "   "),
+            Operation::Create {
+                title: "Runbook".to_string(),
+                parent_id: None,
+                body: String::new(),
+            },
+            rename("12", "Runbook"),
+            Operation::Delete {
+                id: "13".to_string(),
+            },
+        ];
+        let problems = check_renames(&test_api(), &test_space(), &operations).unwrap();
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("Page 10") && problems[0].contains("empty title"));
+        assert!(problems[1].contains("Page 11") && problems[1].contains("empty title"));
+        assert!(problems[2].contains("Page 12") && problems[2].contains("creates a page"));
+    }
+
+    #[test]
+    fn plans_without_renames_have_nothing_to_check() {
+        let operations = vec![Operation::Delete {
+            id: "13".to_string(),
+        }];
+        assert!(check_renames(&test_api(), &test_space(), &operations)
+            .unwrap()
+            .is_empty());
+        assert!(check_renames(&test_api(), &test_space(), &[])
+            .unwrap()
+            .is_empty());
+    }
+
+    fn pattern(text: &str) -> Regex {
+        Regex::new(&regex::escape(text)).unwrap()
+    }
+
+    #[test]
+    fn plain_replacements_in_storage_ignore_regex_syntax() {
+        let body = "<p>v1.0 and v1x0, cost $1</p>";
+        let replaced =
+            replace_in_body(body, BodyFormat::Storage, &pattern("v1.0"), "$1", false).unwrap();
+        assert_eq!(replaced, "<p>$1 and v1x0, cost $1</p>");
+    }
+
+    #[test]
+    fn regex_replacements_expand_groups() {
+        let pattern = Regex::new(r"v(\d)\.(\d)").unwrap();
+        let replaced =
+            replace_in_body("<p>v1.0</p>", BodyFormat::Storage, &pattern, "v$2.$1", true).unwrap();
+        assert_eq!(replaced, "<p>v0.1</p>");
+    }
+
+    #[test]
+    fn adf_replacements_only_touch_text_and_links() {
+        let body = r#"{"type":"doc","content":[{"type":"paragraph","content":[{"type":"text","text":"old site","marks":[{"type":"link","attrs":{"href":"https://old.example"}}]},{"type":"status","attrs":{"text":"old"}}]}]}"#;
+        let replaced =
+            replace_in_body(body, BodyFormat::Adf, &pattern("old"), "new", false).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&replaced).unwrap();
+        let content = &doc["content"][0]["content"];
+        assert_eq!(content[0]["text"], "new site");
+        assert_eq!(
+            content[0]["marks"][0]["attrs"]["href"],
+            "https://new.example"
+        );
+        assert_eq!(content[1]["attrs"]["text"], "old");
+    }
+
+    #[test]
+    fn unmatched_adf_is_returned_as_it_was() {
+        let body = r#"{"version":1,"type":"doc","content":[]}"#;
+        let replaced =
+            replace_in_body(body, BodyFormat::Adf, &pattern("old"), "new", false).unwrap();
+        assert_eq!(replaced, body);
+        assert!(replace_in_body(
+            "<p>not json</p>",
+            BodyFormat::Adf,
+            &pattern("old"),
+            "new",
+            false
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,965 @@
+//! Conversion between Atlassian Document Format (ADF) and markdown, for pages
+//! whose body is stored as ADF json rather than storage format.
+//!
+//! Expands are written as `<details>` html blocks and panels as GitHub style
+//! alerts (`> [!NOTE]`). Nodes with no markdown equivalent (macros,
+//! mentions...) are kept verbatim as json so they survive the round trip:
+//! block nodes in a fenced code block with the `adf` info string, inline nodes
+//! in a code span starting with `adf:`. Tables are only written as markdown
+//! when every cell holds a single line of text; anything richer is kept as
+//! json too.
+
+use anyhow::{Context, Result};
+use pulldown_cmark::{BlockQuoteKind, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::convert::Toc;
+
+const PROTECTED_LANGUAGE: &str = "adf";
+const PROTECTED_PREFIX: &str = "adf:";
+
+/// Converts an ADF document, as the json string Confluence returns, to markdown
+pub fn to_markdown(adf: &str) -> Result<String> {
+    let doc: Value = serde_json::from_str(adf).context("The page body is not valid ADF")?;
+    Ok(blocks_to_markdown(children(&doc)) + "\n")
+}
+
+/// Converts markdown to an ADF document, as a json string ready to upload
+pub fn from_markdown(markdown: &str) -> Result<String> {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_GFM);
+    let mut builder = Builder::new();
+    // [TOC] markers are found in the source, where an escaped \[TOC\] can
+    // still be told apart from one
+    let mut in_toc = false;
+    for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Paragraph) => {
+                if let Some(toc) = Toc::parse(&markdown[range]) {
+                    builder.add_block(toc_extension(&toc));
+                    in_toc = true;
+                    continue;
+                }
+            }
+            Event::End(TagEnd::Paragraph) if in_toc => {
+                in_toc = false;
+                continue;
+            }
+            _ if in_toc => continue,
+            _ => (),
+        }
+        builder.event(event)?;
+    }
+    Ok(serde_json::to_string(&builder.finish())?)
+}
+
+// ADF to markdown
+
+fn node_type(node: &Value) -> &str {
+    node["type"].as_str().unwrap_or("")
+}
+
+fn children(node: &Value) -> &[Value] {
+    node["content"].as_array().map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn blocks_to_markdown(nodes: &[Value]) -> String {
+    nodes
+        .iter()
+        .map(block_to_markdown)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn block_to_markdown(node: &Value) -> String {
+    match node_type(node) {
+        "paragraph" => inlines_to_markdown(children(node))
+            .split("\\\n")
+            .map(escape_block_start)
+            .collect::<Vec<_>>()
+            .join("\\\n"),
+        "heading" => {
+            let level = node["attrs"]["level"].as_u64().unwrap_or(1) as usize;
+            format!(
+                "{} {}",
+                "#".repeat(level),
+                inlines_to_markdown(children(node))
+            )
+        }
+        "bulletList" => list_to_markdown(node, |_| "- ".to_string()),
+        "orderedList" => {
+            let start = node["attrs"]["order"].as_u64().unwrap_or(1);
+            list_to_markdown(node, |i| format!("{}. ", start + i as u64))
+        }
+        "taskList" => task_list_to_markdown(node),
+        "codeBlock" => {
+            let language = node["attrs"]["language"].as_str().unwrap_or("");
+            let text: String = children(node)
+                .iter()
+                .filter_map(|child| child["text"].as_str())
+                .collect();
+            let fence = fence_for(&text);
+            format!("{}{}\n{}\n{}", fence, language, text, fence)
+        }
+        "blockquote" => prefix_lines(&blocks_to_markdown(children(node)), "> ", "> "),
+        "rule" => "---".to_string(),
+        "table" => table_to_markdown(node).unwrap_or_else(|| protected_block(node)),
+        "panel" => match PANEL_ALERTS
+            .iter()
+            .find(|(panel, _)| node["attrs"]["panelType"] == *panel)
+        {
+            Some((_, kind)) => prefix_lines(
+                &format!("[!{}]\n{}", kind, blocks_to_markdown(children(node))),
+                "> ",
+                "> ",
+            ),
+            None => protected_block(node),
+        },
+        "extension" => toc_marker(node).unwrap_or_else(|| protected_block(node)),
+        "expand" | "nestedExpand" => format!(
+            "<details>\n<summary>{}</summary>\n\n{}\n\n</details>",
+            node["attrs"]["title"].as_str().unwrap_or(""),
+            blocks_to_markdown(children(node))
+        ),
+        _ => protected_block(node),
+    }
+}
+
+// Panel types and the alert kinds they are written as. Tip panels are
+// written as tips too, but tips become success panels as the editor makes them.
+const PANEL_ALERTS: [(&str, &str); 6] = [
+    ("info", "NOTE"),
+    ("success", "TIP"),
+    ("note", "IMPORTANT"),
+    ("warning", "WARNING"),
+    ("error", "CAUTION"),
+    ("tip", "TIP"),
+];
+
+// Only table of contents macros have a markdown form
+fn toc_marker(node: &Value) -> Option<String> {
+    if node["attrs"]["extensionKey"] != "toc" {
+        return None;
+    }
+    let parameters = match node["attrs"]["parameters"]["macroParams"].as_object() {
+        Some(parameters) => parameters
+            .iter()
+            .map(|(name, value)| Some((name.as_str(), value["value"].as_str()?)))
+            .collect::<Option<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    Some(Toc::from_parameters(parameters)?.marker())
+}
+
+fn list_to_markdown(node: &Value, marker: impl Fn(usize) -> String) -> String {
+    children(node)
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let marker = marker(i);
+            let blocks = children(item);
+            let mut body = String::new();
+            for (j, block) in blocks.iter().enumerate() {
+                if j > 0 {
+                    // A nested list continues the item, anything else is a
+                    // new paragraph within it
+                    body.push_str(match node_type(block) {
+                        "bulletList" | "orderedList" | "taskList" => "\n",
+                        _ => "\n\n",
+                    });
+                }
+                body.push_str(&block_to_markdown(block));
+            }
+            prefix_lines(&body, &marker, &" ".repeat(marker.len()))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Nested task lists are children of the task list itself rather than of a
+// task item
+fn task_list_to_markdown(node: &Value) -> String {
+    children(node)
+        .iter()
+        .map(|child| match node_type(child) {
+            "taskItem" => {
+                let marker = match child["attrs"]["state"].as_str() {
+                    Some("DONE") => "- [x] ",
+                    _ => "- [ ] ",
+                };
+                prefix_lines(&inlines_to_markdown(children(child)), marker, "  ")
+            }
+            "taskList" => prefix_lines(&task_list_to_markdown(child), "  ", "  "),
+            _ => protected_block(child),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Returns None when the table can't be written as a markdown table without
+// losing something
+fn table_to_markdown(node: &Value) -> Option<String> {
+    let mut rows = Vec::new();
+    for (i, row) in children(node).iter().enumerate() {
+        let mut cells = Vec::new();
+        for cell in children(row) {
+            let header = node_type(cell) == "tableHeader";
+            let spans = ["colspan", "rowspan"]
+                .iter()
+                .any(|span| cell["attrs"][span].as_u64().unwrap_or(1) > 1);
+            // Markdown tables only have a header row, and every cell is a
+            // single line of inline content
+            if header != (i == 0) || spans {
+                return None;
+            }
+            let text = match children(cell) {
+                [] => String::new(),
+                [paragraph] if node_type(paragraph) == "paragraph" => {
+                    inlines_to_markdown(children(paragraph))
+                }
+                _ => return None,
+            };
+            if text.contains('\n') {
+                return None;
+            }
+            cells.push(text.replace('|', "\\|"));
+        }
+        rows.push(format!("| {} |", cells.join(" | ")));
+        if i == 0 {
+            rows.push(format!("|{}", " --- |".repeat(cells.len())));
+        }
+    }
+    Some(rows.join("\n"))
+}
+
+fn inlines_to_markdown(nodes: &[Value]) -> String {
+    let mut out = String::new();
+    let mut open: Vec<&Value> = Vec::new();
+    // Whitespace at the end of marked text, written once the marks are closed
+    // since markdown delimiters can't sit next to whitespace on the inside
+    let mut trailing = String::new();
+    for node in nodes {
+        let text = match node_type(node) {
+            "text" => node["text"].as_str().unwrap_or(""),
+            _ => "",
+        };
+        let mut marks: Vec<&Value> = node["marks"]
+            .as_array()
+            .map(|marks| {
+                marks
+                    .iter()
+                    .filter(|mark| node_type(mark) != "code")
+                    .collect()
+            })
+            .unwrap_or_default();
+        marks.sort_by_key(|mark| mark_rank(mark));
+
+        let core = text.trim();
+        let leading = &text[..text.len() - text.trim_start().len()];
+        if node_type(node) == "text" && core.is_empty() {
+            trailing.push_str(text);
+            continue;
+        }
+
+        let keep = open
+            .iter()
+            .zip(marks.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while open.len() > keep {
+            out.push_str(&close_mark(open.pop().expect("open is longer than keep")));
+        }
+        out.push_str(&trailing);
+        trailing.clear();
+        out.push_str(leading);
+        for mark in &marks[keep..] {
+            out.push_str(open_mark(mark));
+            open.push(mark);
+        }
+
+        match node_type(node) {
+            "text" => {
+                let is_code = node["marks"]
+                    .as_array()
+                    .is_some_and(|marks| marks.iter().any(|mark| node_type(mark) == "code"));
+                if is_code {
+                    out.push_str(&code_span(core));
+                } else {
+                    out.push_str(&escape(core));
+                }
+                trailing.push_str(&text[leading.len() + core.len()..]);
+            }
+            "hardBreak" => out.push_str("\\\n"),
+            "emoji" => match node["attrs"]["shortName"].as_str() {
+                Some(short_name) => out.push_str(short_name),
+                None => out.push_str(&protected_inline(node)),
+            },
+            "status" => match status_shortcode(node) {
+                Some(shortcode) => out.push_str(&shortcode),
+                None => out.push_str(&protected_inline(node)),
+            },
+            "date" => match node["attrs"]["timestamp"]
+                .as_str()
+                .and_then(timestamp_to_date)
+            {
+                Some(date) => out.push_str(&format!("{{date:{}}}", date)),
+                None => out.push_str(&protected_inline(node)),
+            },
+            _ => out.push_str(&protected_inline(node)),
+        }
+    }
+    while let Some(mark) = open.pop() {
+        out.push_str(&close_mark(mark));
+    }
+    out.push_str(&trailing);
+    out
+}
+
+// Keeps nesting of marks stable between neighbouring text nodes
+fn mark_rank(mark: &Value) -> usize {
+    match node_type(mark) {
+        "link" => 0,
+        "strong" => 1,
+        "em" => 2,
+        "strike" => 3,
+        _ => 4,
+    }
+}
+
+fn open_mark(mark: &Value) -> &str {
+    match node_type(mark) {
+        "link" => "[",
+        "strong" => "**",
+        "em" => "*",
+        "strike" => "~~",
+        _ => "",
+    }
+}
+
+fn close_mark(mark: &Value) -> String {
+    match node_type(mark) {
+        "link" => {
+            let href = mark["attrs"]["href"].as_str().unwrap_or("");
+            if href.contains([' ', '(', ')']) {
+                format!("](<{}>)", href)
+            } else {
+                format!("]({})", href)
+            }
+        }
+        _ => open_mark(mark).to_string(),
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '~' | '#'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Stops paragraphs that start like a list item or setext underline being read
+// as one
+fn escape_block_start(text: &str) -> String {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if text.starts_with(['-', '+', '=']) {
+        format!("\\{}", text)
+    } else if digits > 0 && text[digits..].starts_with(['.', ')']) {
+        format!("{}\\{}", &text[..digits], &text[digits..])
+    } else {
+        text.to_string()
+    }
+}
+
+fn code_span(text: &str) -> String {
+    let ticks = "`".repeat(longest_run(text, '`') + 1);
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{} {} {}", ticks, text, ticks)
+    } else {
+        format!("{}{}{}", ticks, text, ticks)
+    }
+}
+
+fn fence_for(text: &str) -> String {
+    "`".repeat((longest_run(text, '`') + 1).max(3))
+}
+
+fn longest_run(text: &str, c: char) -> usize {
+    text.split(|other| other != c)
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+fn prefix_lines(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let prefix = if i == 0 { first } else { rest };
+            // Don't leave trailing whitespace on blank lines
+            if line.is_empty() {
+                prefix.trim_end().to_string()
+            } else {
+                format!("{}{}", prefix, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn protected_block(node: &Value) -> String {
+    let json = serde_json::to_string_pretty(node).expect("a json value always serializes");
+    let fence = fence_for(&json);
+    format!("{}{}\n{}\n{}", fence, PROTECTED_LANGUAGE, json, fence)
+}
+
+// ADF's neutral status is grey in storage format, which is the name the
+// shortcodes use for both
+const STATUS_COLOURS: [(&str, &str); 6] = [
+    ("grey", "neutral"),
+    ("red", "red"),
+    ("yellow", "yellow"),
+    ("green", "green"),
+    ("blue", "blue"),
+    ("purple", "purple"),
+];
+
+fn status_shortcode(node: &Value) -> Option<String> {
+    let colour = node["attrs"]["color"].as_str().unwrap_or("neutral");
+    let (shortcode_colour, _) = STATUS_COLOURS.iter().find(|(_, adf)| *adf == colour)?;
+    let text = node["attrs"]["text"].as_str()?;
+    // The shortcode can't hold these, so such statuses stay protected
+    if text.contains(['}', '\n']) {
+        return None;
+    }
+    Some(format!("{{status:{}|{}}}", shortcode_colour, text))
+}
+
+fn protected_inline(node: &Value) -> String {
+    code_span(&format!("{}{}", PROTECTED_PREFIX, node))
+}
+
+// Markdown to ADF
+
+// Builds the document from pulldown-cmark events. Open nodes are kept on a
+// stack and added to their parent when closed.
+struct Builder {
+    stack: Vec<OpenNode>,
+    marks: Vec<Value>,
+    in_table_head: bool,
+    next_local_id: usize,
+    shortcode: Regex,
+    // The text of the html block being read, which is only known to be a
+    // <details> tag once it has all been read
+    html_block: Option<String>,
+    details: Regex,
+}
+
+struct OpenNode {
+    node: Value,
+    // Paragraphs opened to hold inline content that markdown doesn't wrap in
+    // one, e.g. in tight list items and table cells
+    implicit: bool,
+}
+
+impl Builder {
+    fn new() -> Builder {
+        Builder {
+            stack: vec![OpenNode {
+                node: json!({"type": "doc", "version": 1, "content": []}),
+                implicit: false,
+            }],
+            marks: Vec::new(),
+            in_table_head: false,
+            next_local_id: 0,
+            shortcode: Regex::new(
+                r"(:[a-z][a-z0-9_+-]*:)|\{status:(grey|red|yellow|green|blue|purple)\|([^}\n]*)\}|\{date:(\d{4}-\d{2}-\d{2})\}",
+            )
+            .expect("regex should always compile"),
+            html_block: None,
+            details: Regex::new(r"(?s)^\s*<details>\s*(?:<summary>(.*?)</summary>)?\s*$")
+                .expect("regex should always compile"),
+        }
+    }
+
+    fn event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Start(Tag::HtmlBlock) => self.html_block = Some(String::new()),
+            Event::End(TagEnd::HtmlBlock) => self.end_html_block()?,
+            Event::Html(html) if self.html_block.is_some() => {
+                if let Some(block) = &mut self.html_block {
+                    block.push_str(&html);
+                }
+            }
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag)?,
+            Event::Text(text) if self.top_type() == "codeBlock" => {
+                self.append(json!({"type": "text", "text": text.as_ref()}))
+            }
+            Event::Text(text) => self.add_text(&text),
+            Event::Code(code) => match code.strip_prefix(PROTECTED_PREFIX) {
+                Some(json) => {
+                    let node = serde_json::from_str(json)
+                        .with_context(|| format!("Invalid ADF in `{}`", code))?;
+                    self.add_inline(node);
+                }
+                None => {
+                    let mut marks = self.marks.clone();
+                    marks.push(json!({"type": "code"}));
+                    self.add_inline(json!({"type": "text", "text": code.as_ref(), "marks": marks}));
+                }
+            },
+            Event::Html(html) | Event::InlineHtml(html) => self.add_text(&html),
+            Event::SoftBreak => self.add_text(" "),
+            Event::HardBreak => self.add_inline(json!({"type": "hardBreak"})),
+            Event::Rule => self.add_block(json!({"type": "rule"})),
+            Event::TaskListMarker(checked) => self.mark_task(checked),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, tag: Tag) {
+        let node = match tag {
+            Tag::Paragraph => json!({"type": "paragraph", "content": []}),
+            Tag::Heading { level, .. } => {
+                json!({"type": "heading", "attrs": {"level": level as u64}, "content": []})
+            }
+            Tag::BlockQuote(Some(kind)) => {
+                let kind = match kind {
+                    BlockQuoteKind::Note => "NOTE",
+                    BlockQuoteKind::Tip => "TIP",
+                    BlockQuoteKind::Important => "IMPORTANT",
+                    BlockQuoteKind::Warning => "WARNING",
+                    BlockQuoteKind::Caution => "CAUTION",
+                };
+                let (panel, _) = PANEL_ALERTS
+                    .iter()
+                    .find(|(_, alert)| *alert == kind)
+                    .expect("every alert kind has a panel");
+                json!({"type": "panel", "attrs": {"panelType": panel}, "content": []})
+            }
+            Tag::BlockQuote(None) => json!({"type": "blockquote", "content": []}),
+            Tag::CodeBlock(kind) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => {
+                        info.split_whitespace().next().unwrap_or("").to_string()
+                    }
+                    CodeBlockKind::Indented => String::new(),
+                };
+                if language.is_empty() {
+                    json!({"type": "codeBlock", "content": []})
+                } else {
+                    json!({"type": "codeBlock", "attrs": {"language": language}, "content": []})
+                }
+            }
+            Tag::List(Some(start)) => {
+                json!({"type": "orderedList", "attrs": {"order": start}, "content": []})
+            }
+            Tag::List(None) => json!({"type": "bulletList", "content": []}),
+            Tag::Item => json!({"type": "listItem", "content": []}),
+            Tag::Table(_) => json!({"type": "table", "content": []}),
+            Tag::TableHead => {
+                self.in_table_head = true;
+                json!({"type": "tableRow", "content": []})
+            }
+            Tag::TableRow => json!({"type": "tableRow", "content": []}),
+            Tag::TableCell if self.in_table_head => json!({"type": "tableHeader", "content": []}),
+            Tag::TableCell => json!({"type": "tableCell", "content": []}),
+            Tag::Emphasis => return self.marks.push(json!({"type": "em"})),
+            Tag::Strong => return self.marks.push(json!({"type": "strong"})),
+            Tag::Strikethrough => return self.marks.push(json!({"type": "strike"})),
+            // Images need uploading as attachments first, so they stay links
+            Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                return self
+                    .marks
+                    .push(json!({"type": "link", "attrs": {"href": dest_url.as_ref()}}))
+            }
+            _ => return,
+        };
+        self.close_implicit();
+        self.stack.push(OpenNode {
+            node,
+            implicit: false,
+        });
+    }
+
+    fn end(&mut self, tag: TagEnd) -> Result<()> {
+        match tag {
+            TagEnd::Emphasis
+            | TagEnd::Strong
+            | TagEnd::Strikethrough
+            | TagEnd::Link
+            | TagEnd::Image => {
+                self.marks.pop();
+            }
+            TagEnd::Paragraph
+            | TagEnd::Heading(_)
+            | TagEnd::BlockQuote(_)
+            | TagEnd::CodeBlock
+            | TagEnd::List(_)
+            | TagEnd::Item
+            | TagEnd::Table
+            | TagEnd::TableHead
+            | TagEnd::TableRow
+            | TagEnd::TableCell => {
+                self.close_implicit();
+                if tag == TagEnd::TableHead {
+                    self.in_table_head = false;
+                }
+                self.pop()?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // <details> blocks open and close expands, other html is kept as text.
+    // </details> only closes an expand it is directly inside, not one around
+    // the list or quote it is in, which is kept as text too
+    fn end_html_block(&mut self) -> Result<()> {
+        let block = self.html_block.take().unwrap_or_default();
+        if let Some(caps) = self.details.captures(&block) {
+            let title = caps.get(1).map_or("", |title| title.as_str().trim());
+            // Expands can't hold expands, and tables need the nested kind
+            let nested = self.stack.iter().any(|open| {
+                matches!(
+                    node_type(&open.node),
+                    "expand" | "nestedExpand" | "tableCell" | "tableHeader"
+                )
+            });
+            let expand_type = if nested { "nestedExpand" } else { "expand" };
+            self.close_implicit();
+            self.stack.push(OpenNode {
+                node: json!({"type": expand_type, "attrs": {"title": title}, "content": []}),
+                implicit: false,
+            });
+        } else if block.trim() == "</details>"
+            && self
+                .stack
+                .iter()
+                .rev()
+                .find(|open| !open.implicit)
+                .is_some_and(|open| matches!(node_type(&open.node), "expand" | "nestedExpand"))
+        {
+            self.close_implicit();
+            self.pop()?;
+        } else {
+            self.add_text(&block);
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Value {
+        self.close_implicit();
+        // Expands missing their </details> end with the document
+        while self.stack.len() > 1 {
+            let open = self.stack.pop().expect("checked above");
+            self.append(open.node);
+        }
+        self.stack.pop().expect("the doc is never popped").node
+    }
+
+    fn top_type(&self) -> &str {
+        node_type(&self.stack.last().expect("the doc is never popped").node)
+    }
+
+    fn append(&mut self, node: Value) {
+        let top = &mut self.stack.last_mut().expect("the doc is never popped").node;
+        top["content"]
+            .as_array_mut()
+            .expect("open nodes always have content")
+            .push(node);
+    }
+
+    fn add_block(&mut self, node: Value) {
+        self.close_implicit();
+        self.append(node);
+    }
+
+    fn add_inline(&mut self, node: Value) {
+        if matches!(
+            self.top_type(),
+            "doc" | "listItem" | "blockquote" | "tableCell" | "tableHeader"
+        ) {
+            self.stack.push(OpenNode {
+                node: json!({"type": "paragraph", "content": []}),
+                implicit: true,
+            });
+        }
+        self.append(node);
+    }
+
+    // Splits out emoji, status and date shortcodes, the inverse of writing
+    // those nodes as shortcodes
+    fn add_text(&mut self, text: &str) {
+        let mut last = 0;
+        let matches: Vec<_> = self
+            .shortcode
+            .captures_iter(text)
+            .filter_map(|caps| {
+                let range = caps.get(0).expect("group 0 is the whole match").range();
+                let node = if let Some(short_name) = caps.get(1) {
+                    json!({"type": "emoji", "attrs": {"shortName": short_name.as_str()}})
+                } else if let Some(date) = caps.get(4) {
+                    json!({"type": "date", "attrs": {"timestamp": date_to_timestamp(date.as_str())?}})
+                } else {
+                    let (_, colour) = STATUS_COLOURS.iter().find(|(shortcode, _)| *shortcode == &caps[2])?;
+                    json!({"type": "status", "attrs": {"text": &caps[3], "color": colour}})
+                };
+                Some((range, node))
+            })
+            .collect();
+        for (range, node) in matches {
+            self.add_marked_text(&text[last..range.start]);
+            self.add_inline(node);
+            last = range.end;
+        }
+        self.add_marked_text(&text[last..]);
+    }
+
+    fn add_marked_text(&mut self, text: &str) {
+        // ADF rejects empty text nodes
+        if text.is_empty() {
+            return;
+        }
+        let node = if self.marks.is_empty() {
+            json!({"type": "text", "text": text})
+        } else {
+            json!({"type": "text", "text": text, "marks": self.marks})
+        };
+        // Markdown escapes split text into several events, join them back up
+        let top = &mut self.stack.last_mut().expect("the doc is never popped").node;
+        if let Some(last) = top["content"].as_array_mut().and_then(|c| c.last_mut()) {
+            if node_type(last) == "text" && last["marks"] == node["marks"] {
+                let joined = format!("{}{}", last["text"].as_str().unwrap_or(""), text);
+                last["text"] = Value::String(joined);
+                return;
+            }
+        }
+        self.add_inline(node);
+    }
+
+    fn close_implicit(&mut self) {
+        if self.stack.last().is_some_and(|open| open.implicit) {
+            let open = self.stack.pop().expect("checked above");
+            self.append(open.node);
+        }
+    }
+
+    // Turns the innermost list item into a task, and its list into a task list
+    fn mark_task(&mut self, checked: bool) {
+        let Some(item) = self
+            .stack
+            .iter()
+            .rposition(|open| node_type(&open.node) == "listItem")
+        else {
+            return;
+        };
+        let local_id = self.local_id();
+        self.stack[item].node = json!({
+            "type": "taskItem",
+            "attrs": {"localId": local_id, "state": if checked { "DONE" } else { "TODO" }},
+            "content": self.stack[item].node["content"].take(),
+        });
+        if item > 0 && node_type(&self.stack[item - 1].node) != "taskList" {
+            let local_id = self.local_id();
+            self.stack[item - 1].node = json!({
+                "type": "taskList",
+                "attrs": {"localId": local_id},
+                "content": self.stack[item - 1].node["content"].take(),
+            });
+        }
+    }
+
+    fn local_id(&mut self) -> String {
+        self.next_local_id += 1;
+        self.next_local_id.to_string()
+    }
+
+    fn pop(&mut self) -> Result<()> {
+        let mut open = self.stack.pop().expect("end events match start events");
+        let node = &mut open.node;
+        match node_type(node) {
+            "codeBlock" => {
+                let text: String = children(node)
+                    .iter()
+                    .filter_map(|child| child["text"].as_str())
+                    .collect();
+                let text = text.strip_suffix('\n').unwrap_or(&text).to_string();
+                if node["attrs"]["language"] == PROTECTED_LANGUAGE {
+                    let protected = serde_json::from_str(&text)
+                        .with_context(|| format!("Invalid ADF in adf code block:\n{}", text))?;
+                    self.append(protected);
+                    return Ok(());
+                }
+                node["content"] = if text.is_empty() {
+                    json!([])
+                } else {
+                    json!([{"type": "text", "text": text}])
+                };
+            }
+            // Task items hold inline content directly, and nested task lists
+            // go after the item in the parent list
+            "taskItem" => {
+                let mut inline = Vec::new();
+                let mut nested = Vec::new();
+                for child in node["content"]
+                    .take()
+                    .as_array_mut()
+                    .expect("open nodes always have content")
+                    .drain(..)
+                {
+                    match node_type(&child) {
+                        "paragraph" => {
+                            if !inline.is_empty() {
+                                inline.push(json!({"type": "hardBreak"}));
+                            }
+                            inline.extend(children(&child).iter().cloned());
+                        }
+                        "taskList" | "bulletList" | "orderedList" | "codeBlock" | "blockquote"
+                        | "heading" | "rule" | "table" => nested.push(child),
+                        _ => inline.push(child),
+                    }
+                }
+                node["content"] = Value::Array(inline);
+                self.append(open.node);
+                for child in nested {
+                    self.append(child);
+                }
+                return Ok(());
+            }
+            // These need at least one block
+            "listItem" | "tableCell" | "tableHeader" | "expand" | "nestedExpand"
+                if children(node).is_empty() =>
+            {
+                node["content"] = json!([{"type": "paragraph", "content": []}]);
+            }
+            _ => (),
+        }
+        self.append(open.node);
+        Ok(())
+    }
+}
+
+fn toc_extension(toc: &Toc) -> Value {
+    let parameters: serde_json::Map<String, Value> = toc
+        .parameters()
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), json!({ "value": value })))
+        .collect();
+    json!({
+        "type": "extension",
+        "attrs": {
+            "extensionType": "com.atlassian.confluence.macro.core",
+            "extensionKey": "toc",
+            "parameters": {"macroParams": parameters},
+            "layout": "default",
+        },
+    })
+}
+
+// Dates
+
+// ADF dates are midnight UTC as a millisecond timestamp in a string. The day
+// conversions are Howard Hinnant's civil calendar algorithms.
+fn timestamp_to_date(timestamp: &str) -> Option<String> {
+    let days = timestamp.parse::<i64>().ok()?.div_euclid(86_400_000);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+fn date_to_timestamp(date: &str) -> Option<String> {
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let day_of_year = (153 * mp + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Some((days * 86_400_000).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::tests::markdown_document;
+    use crate::convert::text_blocks;
+    use proptest::prelude::*;
+
+    fn protected_node() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            "[a-z]{1,8}".prop_map(|key| json!({
+                "type": "extension",
+                "attrs": {"extensionType": "com.example", "extensionKey": key}
+            })),
+            "[a-z]{1,8}".prop_map(|id| json!({
+                "type": "paragraph",
+                "content": [
+                    {"type": "text", "text": "ask "},
+                    {"type": "mention", "attrs": {"id": id, "text": format!("@{}", id)}}
+                ]
+            })),
+        ]
+    }
+
+    // Every string in the document, to look for protected json that wasn't
+    // turned back into nodes
+    fn strings(value: &Value) -> Vec<String> {
+        match value {
+            Value::String(text) => vec![text.clone()],
+            Value::Array(values) => values.iter().flat_map(strings).collect(),
+            Value::Object(map) => map.values().flat_map(strings).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn round_trip_keeps_the_text(markdown in markdown_document()) {
+            let round_tripped = to_markdown(&from_markdown(&markdown).unwrap()).unwrap();
+            prop_assert_eq!(text_blocks(&round_tripped), text_blocks(&markdown));
+        }
+
+        #[test]
+        fn protected_nodes_come_back_as_nodes(
+            nodes in prop::collection::vec(protected_node(), 1..5)
+        ) {
+            let doc = json!({"type": "doc", "version": 1, "content": nodes});
+            let markdown = to_markdown(&doc.to_string()).unwrap();
+            let round_tripped: Value =
+                serde_json::from_str(&from_markdown(&markdown).unwrap()).unwrap();
+            prop_assert_eq!(&round_tripped["content"], &doc["content"]);
+            let leaked = strings(&round_tripped)
+                .into_iter()
+                .any(|text| text == PROTECTED_LANGUAGE || text.starts_with(PROTECTED_PREFIX));
+            prop_assert!(!leaked, "{}", round_tripped);
+        }
+    }
+}
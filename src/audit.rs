@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use concmd::Config;
+
+// Append-only log of every change concmd makes on Confluence, stored as one
+// json object per line alongside the config file: pages created, updated,
+// moved or deleted, and changes around them such as labels, restrictions,
+// watches, properties, comments and completed tasks. Entries are only ever
+// added, so the log can be used to work out what happened when a page was
+// overwritten. Shown with concmd log.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Create,
+    Update,
+    // An update made by concmd undo, restoring an earlier version
+    Revert,
+    Rename,
+    Move,
+    Archive,
+    Unarchive,
+    Delete,
+    Purge,
+    Label,
+    Unlabel,
+    Restrict,
+    Watch,
+    Unwatch,
+    Property,
+    Comment,
+    Complete,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Operation::Create => "create",
+            Operation::Update => "update",
+            Operation::Revert => "revert",
+            Operation::Rename => "rename",
+            Operation::Move => "move",
+            Operation::Archive => "archive",
+            Operation::Unarchive => "unarchive",
+            Operation::Delete => "delete",
+            Operation::Purge => "purge",
+            Operation::Label => "label",
+            Operation::Unlabel => "unlabel",
+            Operation::Restrict => "restrict",
+            Operation::Watch => "watch",
+            Operation::Unwatch => "unwatch",
+            Operation::Property => "property",
+            Operation::Comment => "comment",
+            Operation::Complete => "complete",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    // UTC, e.g. 2024-01-31T09:30:00Z
+    pub timestamp: String,
+    pub operation: Operation,
+    pub id: String,
+    // The version the page was left at, where concmd knows it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    // What changed, for operations that don't change the page itself, e.g.
+    // the label added or the property set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    // The site and account the change was made with
+    pub site: String,
+    pub user: String,
+}
+
+fn log_path() -> PathBuf {
+    let mut path = home::home_dir().expect("home dir should always exist");
+    path.push(".config/concmd/audit.jsonl");
+    path
+}
+
+// Called once an operation has succeeded. Failing to log is only a warning, as
+// the change has already been made by then.
+pub fn record(
+    config: &Config,
+    operation: Operation,
+    id: &str,
+    version: Option<usize>,
+    title: Option<&str>,
+) {
+    append_entry(Entry {
+        timestamp: format_timestamp(SystemTime::now()),
+        operation,
+        id: id.to_string(),
+        version,
+        title: title.map(str::to_string),
+        detail: None,
+        site: config.api.confluence_domain.clone(),
+        user: config.api.username.clone(),
+    });
+}
+
+// For changes around a page rather than to its content, id being the page
+// (or space, for watches) and detail what changed
+pub fn record_detail(config: &Config, operation: Operation, id: &str, detail: &str) {
+    append_entry(Entry {
+        timestamp: format_timestamp(SystemTime::now()),
+        operation,
+        id: id.to_string(),
+        version: None,
+        title: None,
+        detail: Some(detail.to_string()),
+        site: config.api.confluence_domain.clone(),
+        user: config.api.username.clone(),
+    });
+}
+
+fn append_entry(entry: Entry) {
+    if let Err(e) = append(&entry) {
+        eprintln!("Warning: could not write to the audit log: {:#}", e);
+    }
+}
+
+fn append(entry: &Entry) -> Result<()> {
+    let path = log_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+// All entries, oldest first
+pub fn read() -> Result<Vec<Entry>> {
+    let contents = match fs::read_to_string(log_path()) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("The audit log is not readable"),
+    };
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Line {} of the audit log could not be parsed", i + 1))
+        })
+        .collect()
+}
+
+// Civil date from days since the epoch, from Howard Hinnant's date algorithms
+pub fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (days, time_of_day) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
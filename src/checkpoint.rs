@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+
+// Progress through a bulk command, saved after every page so that running the
+// same command again after a failure or ctrl-c picks up where it stopped.
+// Pages already done are skipped and pages that failed are tried again. The
+// checkpoint is removed once a run finishes without failures.
+//
+// Checkpoints live in ~/.config/concmd/checkpoints, named by a hash of the
+// command and its arguments, so only an identical command resumes one.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Checkpoint {
+    #[serde(skip)]
+    path: PathBuf,
+    pub command: String,
+    #[serde(default)]
+    completed: BTreeSet<String>,
+    // Page id to the error it failed with on the last run
+    #[serde(default)]
+    failed: BTreeMap<String, String>,
+}
+
+impl Checkpoint {
+    // Loads the checkpoint for command, or starts a new one if there is none or
+    // restart is set
+    pub fn open(command: &str, restart: bool) -> Result<Checkpoint> {
+        let path = checkpoint_path(command);
+        let existing = match restart {
+            true => None,
+            false => match fs::read_to_string(&path) {
+                Ok(contents) => Some(
+                    serde_json::from_str::<Checkpoint>(&contents)
+                        .with_context(|| format!("{} could not be parsed", path.display()))?,
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("{} is not readable", path.display()))
+                }
+            },
+        };
+        let checkpoint = match existing {
+            Some(checkpoint) => Checkpoint { path, ..checkpoint },
+            None => Checkpoint {
+                path,
+                command: command.to_string(),
+                ..Default::default()
+            },
+        };
+        if !checkpoint.completed.is_empty() || !checkpoint.failed.is_empty() {
+            eprintln!(
+                "Resuming from a checkpoint: {} page(s) done, {} to retry. Pass --restart to start over",
+                checkpoint.completed.len(),
+                checkpoint.failed.len()
+            );
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn is_done(&self, id: &str) -> bool {
+        self.completed.contains(id)
+    }
+
+    pub fn complete(&mut self, id: &str) -> Result<()> {
+        self.failed.remove(id);
+        self.completed.insert(id.to_string());
+        self.save()
+    }
+
+    pub fn fail(&mut self, id: &str, error: &str) -> Result<()> {
+        self.failed.insert(id.to_string(), error.to_string());
+        self.save()
+    }
+
+    // Called at the end of a run that got through every page. Anything that
+    // failed is kept for the next run, otherwise the checkpoint is removed.
+    pub fn finish(self) -> Result<()> {
+        if !self.failed.is_empty() {
+            eprintln!(
+                "{} page(s) failed, run the same command again to retry them",
+                self.failed.len()
+            );
+            return self.save();
+        }
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("{} could not be written", self.path.display()))
+    }
+}
+
+// FNV-1a, as the std hasher isn't guaranteed to be stable between releases
+fn checkpoint_path(command: &str) -> PathBuf {
+    let hash = command.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    let mut path = home::home_dir().expect("home dir should always exist");
+    path.push(format!(".config/concmd/checkpoints/{:016x}.json", hash));
+    path
+}
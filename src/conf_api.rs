@@ -1,7 +1,17 @@
-use anyhow::{Ok, Result};
-use reqwest::blocking;
-use serde::{Deserialize, Serialize};
+//! Client for the Confluence Cloud REST api. Uses v2 endpoints where they
+//! exist and falls back to v1 for labels, moves, restrictions, watches and
+//! search. Every call takes the [`Api`] connection details from the config.
+
+use anyhow::{Context, Ok, Result};
+use reqwest::{blocking, Method};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
 use crate::Api;
 
@@ -10,82 +20,1166 @@ pub struct Page {
     pub id: String,
     pub title: String,
     status: String,
+    #[serde(rename = "spaceId")]
+    pub space_id: String,
+    #[serde(rename = "parentId", skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
     pub version: PageVersion,
-    body: Body,
+    body: Storage,
 }
 
 impl Page {
-    // Getter and setter for body to allow for download and upload in the same struct.
-    // Confluence expects slightly different structure for upload than what it gives
-    // for download. This is abstracted away here to make constructing the upload json
-    // a bit easier.
+    /// Getter and setter for body. Confluence gives the body in a different
+    /// shape for download than it expects for upload, so fetched pages are
+    /// read into the upload shape, which is also what the page cache keeps.
     pub fn get_body(&self) -> &String {
-        match &self.body {
-            Body::Upload(storage) => &storage.value,
-            Body::Download(page_body) => &page_body.editor.value,
-        }
+        &self.body.value
     }
 
-    // TODO: fix this logic to allow self-modification of retrived body value
     pub fn set_body(&mut self, body_value: String) {
-        match &mut self.body {
-            Body::Upload(storage) => storage.value = body_value,
-            Body::Download(_) => {
-                let new_body = Storage {
-                    value: body_value,
-                    representation: "storage".to_string()
-                };
-                self.body = Body::Upload(new_body)
-            },
+        self.body.value = body_value;
+    }
+
+    /// The format of the body, which set_body must be given in too
+    pub fn body_format(&self) -> BodyFormat {
+        match self.body.representation.as_str() {
+            "atlas_doc_format" => BodyFormat::Adf,
+            _ => BodyFormat::Storage,
         }
     }
 
-    pub fn get_page_by_id(api: &Api, id: &String) -> Result<Page> {
-        let resp = send_request(api, RequestType::GET, format!(
-                "https://{}/wiki/api/v2/pages/{}?body-format=editor",
-                api.confluence_domain, id
-            ))?
-            .text()?;
-        // Ok(serde_json::from_str::<Page>(&resp)?)
-        let page = serde_json::from_str::<Page>(&resp)?;
-        println!("{:#?}", page);
+    /// Fetches the page with its body as storage format, or as ADF for pages
+    /// made in the new editor so they round trip without conversion on
+    /// Confluence's side. With `cache_pages` set, a cached copy is used as
+    /// long as the page's version hasn't changed. The copy keeps the format it
+    /// was fetched in, so using it costs one version check and nothing else.
+    pub fn get_page_by_id(api: &Api, id: &str) -> Result<Page> {
+        if !api.cache_pages {
+            return Page::download_page(api, id);
+        }
+        if let Some(cached) = read_cached_page(api, id) {
+            if get_page_version(api, id)? == cached.version.number {
+                return Ok(cached);
+            }
+        }
+        let page = Page::download_page(api, id)?;
+        // The cache is only an optimisation, failing to write it isn't an error
+        let _ = write_cached_page(api, &page);
         Ok(page)
     }
 
+    /// Fetches the page as it was at an earlier version, with its body in the
+    /// given format
+    pub fn get_page_at_version(
+        api: &Api,
+        id: &str,
+        version: usize,
+        body_format: BodyFormat,
+    ) -> Result<Page> {
+        let resp = send_request(
+            api,
+            RequestType::GET,
+            format!(
+                "https://{}/wiki/api/v2/pages/{}?version={}&body-format={}",
+                api.confluence_domain,
+                id,
+                version,
+                body_format.request_format()
+            ),
+            &format!("version {} of page {}", version, id),
+        )?;
+        read_page(resp, &format!("version {} of page {}", version, id))
+    }
+
+    fn download_page(api: &Api, id: &str) -> Result<Page> {
+        let body_format = match uses_new_editor(api, id)? {
+            true => BodyFormat::Adf,
+            false => BodyFormat::Storage,
+        };
+        let resp = send_request(api, RequestType::GET, format!(
+                "https://{}/wiki/api/v2/pages/{}?body-format={}",
+                api.confluence_domain, id, body_format.request_format()
+            ), &format!("page {}", id))?;
+        read_page(resp, &format!("page {}", id))
+    }
+
+    pub fn get_labels(&self, api: &Api) -> Result<Vec<String>> {
+        get_labels(api, &self.id)
+    }
+
+    /// Publishes the page as the version after `self.version.number`, which
+    /// must be the page's latest version or Confluence rejects the update as
+    /// a conflict. The number is left at the new version.
     pub fn update_page_by_id(&mut self, api: &Api) -> Result<()> {
-        self.version.number += 1; // don't think this works like this
-        let serialised_body = serde_json::to_string(&self)?;
-        println!("{}", serde_json::to_string_pretty(&self)?);
-        println!("Updating page!");
-
-        let resp = send_request(api, RequestType::PUT(serialised_body), format!(
-            "https://{}/wiki/api/v2/pages/{}",
-            api.confluence_domain, self.id
-        ))?;
-        println!("{:?}", resp.status());
-        if resp.status() == 400 {
-            print!("{:#?}\n", resp.text().unwrap());
+        self.version.number += 1;
+
+        send_json(
+            api,
+            Method::PUT,
+            format!(
+                "https://{}/wiki/api/v2/pages/{}",
+                api.confluence_domain, self.id
+            ),
+            &format!("page {}", self.id),
+            &self,
+        )?;
+        Ok(())
+    }
+}
+
+/// Page operations that don't need the full page body, for workflows that only
+/// have a page id to hand.
+pub fn create_page(
+    api: &Api,
+    space_id: &str,
+    title: &str,
+    parent_id: Option<&str>,
+    body: &str,
+) -> Result<String> {
+    let new_page = NewPage {
+        space_id: space_id.to_string(),
+        status: "current".to_string(),
+        title: title.to_string(),
+        parent_id: parent_id.map(str::to_string),
+        body: Storage {
+            value: body.to_string(),
+            representation: "storage".to_string(),
+        },
+    };
+    let resp = send_json(
+        api,
+        Method::POST,
+        format!("https://{}/wiki/api/v2/pages", api.confluence_domain),
+        &format!("new page \"{}\"", title),
+        &new_page,
+    )?;
+    let created = read_json::<CreatedPage>(resp)?;
+    Ok(created.id)
+}
+
+pub fn rename_page(api: &Api, id: &str, title: &str) -> Result<()> {
+    let body = serde_json::json!({ "status": "current", "title": title });
+    send_request(
+        api,
+        RequestType::PUT(body.to_string()),
+        format!(
+            "https://{}/wiki/api/v2/pages/{}/title",
+            api.confluence_domain, id
+        ),
+        &format!("page {}", id),
+    )?;
+    Ok(())
+}
+
+/// Link to the page in the Confluence web ui
+pub fn page_url(api: &Api, id: &str) -> String {
+    format!(
+        "https://{}/wiki/pages/viewpage.action?pageId={}",
+        api.confluence_domain, id
+    )
+}
+
+pub fn get_labels(api: &Api, id: &str) -> Result<Vec<String>> {
+    let labels: Vec<Label> = get_all_results(
+        api,
+        format!(
+            "https://{}/wiki/api/v2/pages/{}/labels?limit={}",
+            api.confluence_domain, id, api.page_limit
+        ),
+        &format!("labels of page {}", id),
+    )?;
+    Ok(labels.into_iter().map(|label| label.name).collect())
+}
+
+/// The pages and folders above a page, from the top of its space down to its
+/// parent. The v2 ancestors endpoint only returns ids, so this uses v1 to get
+/// the titles in the same request.
+pub fn get_ancestors(api: &Api, id: &str) -> Result<Vec<Content>> {
+    #[derive(Deserialize)]
+    struct WithAncestors {
+        ancestors: Vec<Content>,
+    }
+    let resp = send_request(
+        api,
+        RequestType::GET,
+        format!(
+            "https://{}/wiki/rest/api/content/{}?expand=ancestors",
+            api.confluence_domain, id
+        ),
+        &format!("ancestors of page {}", id),
+    )?;
+    Ok(read_json::<WithAncestors>(resp)?.ancestors)
+}
+
+/// Adds a comment at the bottom of the page. body is in storage format.
+pub fn add_comment(api: &Api, id: &str, body: &str) -> Result<()> {
+    let comment = serde_json::json!({
+        "pageId": id,
+        "body": { "representation": "storage", "value": body },
+    });
+    send_request(
+        api,
+        RequestType::POST(comment.to_string()),
+        format!("https://{}/wiki/api/v2/footer-comments", api.confluence_domain),
+        &format!("comment on page {}", id),
+    )?;
+    Ok(())
+}
+
+/// Labels and moves are not supported by the v2 api yet, so these use v1
+pub fn add_label(api: &Api, id: &str, label: &str) -> Result<()> {
+    let body = serde_json::json!([{ "prefix": "global", "name": label }]);
+    send_request(
+        api,
+        RequestType::POST(body.to_string()),
+        format!(
+            "https://{}/wiki/rest/api/content/{}/label",
+            api.confluence_domain, id
+        ),
+        &format!("page {}", id),
+    )?;
+    Ok(())
+}
+
+pub fn remove_label(api: &Api, id: &str, label: &str) -> Result<()> {
+    let url = reqwest::Url::parse_with_params(
+        &format!(
+            "https://{}/wiki/rest/api/content/{}/label",
+            api.confluence_domain, id
+        ),
+        &[("name", label)],
+    )?;
+    send_request(
+        api,
+        RequestType::DELETE,
+        url.to_string(),
+        &format!("label {} on page {}", label, id),
+    )?;
+    Ok(())
+}
+
+pub fn move_page(api: &Api, id: &str, parent_id: &str) -> Result<()> {
+    send_request(
+        api,
+        RequestType::PUT(String::new()),
+        format!(
+            "https://{}/wiki/rest/api/content/{}/move/append/{}",
+            api.confluence_domain, id, parent_id
+        ),
+        &format!("page {}", id),
+    )?;
+    Ok(())
+}
+
+/// Archiving is only supported by the v1 api. It runs as a long task on
+/// Confluence's side, so the page may take a moment to show as archived.
+pub fn archive_page(api: &Api, id: &str) -> Result<()> {
+    let body = serde_json::json!({ "pages": [{ "id": id.parse::<u64>()? }] });
+    send_request(
+        api,
+        RequestType::POST(body.to_string()),
+        format!("https://{}/wiki/rest/api/content/archive", api.confluence_domain),
+        &format!("page {}", id),
+    )?;
+    Ok(())
+}
+
+/// Restores an archived page by publishing it as current again
+pub fn unarchive_page(api: &Api, id: &str) -> Result<()> {
+    let mut page = Page::get_page_by_id(api, id)?;
+    page.status = "current".to_string();
+    page.update_page_by_id(api)
+}
+
+/// Moves the page to the trash, where it can still be restored from
+pub fn delete_page(api: &Api, id: &str) -> Result<()> {
+    send_request(
+        api,
+        RequestType::DELETE,
+        format!("https://{}/wiki/api/v2/pages/{}", api.confluence_domain, id),
+        &format!("page {}", id),
+    )?;
+    Ok(())
+}
+
+/// Deletes the page for good. Only trashed pages can be purged, so the page is
+/// moved to the trash first.
+pub fn purge_page(api: &Api, id: &str) -> Result<()> {
+    delete_page(api, id)?;
+    send_request(
+        api,
+        RequestType::DELETE,
+        format!(
+            "https://{}/wiki/api/v2/pages/{}?purge=true",
+            api.confluence_domain, id
+        ),
+        &format!("page {}", id),
+    )?;
+    Ok(())
+}
+
+/// A user or group that can be granted view or edit access to a page, parsed
+/// from `user:<account id>` or `group:<group name>`
+#[derive(Debug, Clone)]
+pub enum Restriction {
+    User(String),
+    Group(String),
+}
+
+impl FromStr for Restriction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Restriction> {
+        match s.split_once(':') {
+            Some(("user", account_id)) => Ok(Restriction::User(account_id.to_string())),
+            Some(("group", name)) => Ok(Restriction::Group(name.to_string())),
+            _ => anyhow::bail!("Expected user:<account id> or group:<group name>, got {}", s),
+        }
+    }
+}
+
+impl fmt::Display for Restriction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Restriction::User(account_id) => write!(f, "user:{}", account_id),
+            Restriction::Group(name) => write!(f, "group:{}", name),
+        }
+    }
+}
+
+/// Current restrictions on a page. Restrictions use the v1 api as v2 doesn't
+/// support them yet.
+#[derive(Deserialize, Debug)]
+pub struct PageRestrictions {
+    pub read: OperationRestrictions,
+    pub update: OperationRestrictions,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OperationRestrictions {
+    restrictions: RestrictionLists,
+}
+
+#[derive(Deserialize, Debug)]
+struct RestrictionLists {
+    user: Results<RestrictedUser>,
+    group: Results<RestrictedGroup>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RestrictedUser {
+    display_name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RestrictedGroup {
+    name: String,
+}
+
+impl OperationRestrictions {
+    pub fn users(&self) -> Vec<&str> {
+        self.restrictions
+            .user
+            .results
+            .iter()
+            .map(|user| user.display_name.as_str())
+            .collect()
+    }
+
+    pub fn groups(&self) -> Vec<&str> {
+        self.restrictions
+            .group
+            .results
+            .iter()
+            .map(|group| group.name.as_str())
+            .collect()
+    }
+}
+
+impl PageRestrictions {
+    pub fn get_restrictions(api: &Api, id: &str) -> Result<PageRestrictions> {
+        let resp = send_request(
+            api,
+            RequestType::GET,
+            format!(
+                "https://{}/wiki/rest/api/content/{}/restriction/byOperation?expand=restrictions.user,restrictions.group",
+                api.confluence_domain, id
+            ),
+            &format!("restrictions of page {}", id),
+        )?;
+        read_json::<PageRestrictions>(resp)
+    }
+
+    /// operation is "read" for view access or "update" for edit access
+    pub fn add_restriction(api: &Api, id: &str, operation: &str, restriction: &Restriction) -> Result<()> {
+        let base = format!(
+            "https://{}/wiki/rest/api/content/{}/restriction/byOperation/{}",
+            api.confluence_domain, id, operation
+        );
+        let url = match restriction {
+            Restriction::User(account_id) => reqwest::Url::parse_with_params(
+                &format!("{}/user", base),
+                &[("accountId", account_id)],
+            )?,
+            Restriction::Group(name) => {
+                // Group names can contain spaces so need encoding as a path segment
+                let mut url = reqwest::Url::parse(&format!("{}/group", base))?;
+                url.path_segments_mut()
+                    .map_err(|_| anyhow::anyhow!("Invalid restriction url"))?
+                    .push(name);
+                url
+            }
+        };
+        send_request(
+            api,
+            RequestType::PUT(String::new()),
+            url.to_string(),
+            &format!("restrictions of page {}", id),
+        )?;
+        Ok(())
+    }
+}
+
+/// Something the current user can watch to get notified of changes. Watching
+/// is only supported by the v1 api.
+pub enum WatchTarget {
+    Page(String),
+    Space(String),
+}
+
+impl WatchTarget {
+    fn url(&self, api: &Api) -> String {
+        match self {
+            WatchTarget::Page(id) => format!(
+                "https://{}/wiki/rest/api/user/watch/content/{}",
+                api.confluence_domain, id
+            ),
+            WatchTarget::Space(key) => format!(
+                "https://{}/wiki/rest/api/user/watch/space/{}",
+                api.confluence_domain, key
+            ),
+        }
+    }
+
+    pub fn watch(&self, api: &Api) -> Result<()> {
+        send_request(api, RequestType::POST(String::new()), self.url(api), &self.to_string())?;
+        Ok(())
+    }
+
+    pub fn unwatch(&self, api: &Api) -> Result<()> {
+        send_request(api, RequestType::DELETE, self.url(api), &self.to_string())?;
+        Ok(())
+    }
+
+    pub fn is_watching(&self, api: &Api) -> Result<bool> {
+        let resp = send_request(api, RequestType::GET, self.url(api), &self.to_string())?;
+        let json = read_json::<serde_json::Value>(resp)?;
+        Ok(json["watching"].as_bool().unwrap_or(false))
+    }
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchTarget::Page(id) => write!(f, "page {}", id),
+            WatchTarget::Space(key) => write!(f, "space {}", key),
+        }
+    }
+}
+
+/// A key with a json value stored against a page, for metadata that tools
+/// read and write rather than people
+#[derive(Deserialize, Debug)]
+pub struct ContentProperty {
+    pub id: String,
+    pub key: String,
+    pub value: serde_json::Value,
+    pub version: PageVersion,
+}
+
+impl ContentProperty {
+    pub fn get_properties(api: &Api, page_id: &str) -> Result<Vec<ContentProperty>> {
+        get_all_results(
+            api,
+            format!(
+                "https://{}/wiki/api/v2/pages/{}/properties?limit={}",
+                api.confluence_domain, page_id, api.page_limit
+            ),
+            &format!("properties of page {}", page_id),
+        )
+    }
+
+    pub fn get_property(api: &Api, page_id: &str, key: &str) -> Result<Option<ContentProperty>> {
+        let url = reqwest::Url::parse_with_params(
+            &format!(
+                "https://{}/wiki/api/v2/pages/{}/properties",
+                api.confluence_domain, page_id
+            ),
+            &[("key", key)],
+        )?;
+        let properties: Vec<ContentProperty> = get_all_results(
+            api,
+            url.to_string(),
+            &format!("property {} of page {}", key, page_id),
+        )?;
+        Ok(properties.into_iter().next())
+    }
+
+    /// Creates the property, or replaces its value if the page already has it
+    pub fn set_property(api: &Api, page_id: &str, key: &str, value: serde_json::Value) -> Result<()> {
+        let resource = format!("property {} of page {}", key, page_id);
+        match ContentProperty::get_property(api, page_id, key)? {
+            Some(existing) => {
+                let body = serde_json::json!({
+                    "key": key,
+                    "value": value,
+                    "version": { "number": existing.version.number + 1 },
+                });
+                send_request(
+                    api,
+                    RequestType::PUT(body.to_string()),
+                    format!(
+                        "https://{}/wiki/api/v2/pages/{}/properties/{}",
+                        api.confluence_domain, page_id, existing.id
+                    ),
+                    &resource,
+                )?;
+            }
+            None => {
+                let body = serde_json::json!({ "key": key, "value": value });
+                send_request(
+                    api,
+                    RequestType::POST(body.to_string()),
+                    format!(
+                        "https://{}/wiki/api/v2/pages/{}/properties",
+                        api.confluence_domain, page_id
+                    ),
+                    &resource,
+                )?;
+            }
         }
         Ok(())
     }
 }
 
+/// Lightweight page listing entry, without the body. Listings can run to
+/// thousands of pages so fetching bodies here would be far too slow.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PageSummary {
+    pub id: String,
+    pub title: String,
+    pub status: String,
+    #[serde(rename = "spaceId")]
+    pub space_id: Option<String>,
+    #[serde(rename = "authorId")]
+    pub author_id: Option<String>,
+    #[serde(rename = "ownerId")]
+    pub owner_id: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+    // The latest version, so its creation time is when the page was last updated
+    pub version: Option<PageVersion>,
+}
+
+impl PageSummary {
+    /// Lists every page in the given space, or every page the user can see if
+    /// no space is given. Follows the cursor links until all pages are fetched.
+    pub fn get_pages(api: &Api, space_id: Option<&str>, include_archived: bool) -> Result<Vec<PageSummary>> {
+        let status = match include_archived {
+            true => "status=current&status=archived",
+            false => "status=current",
+        };
+        let url = match space_id {
+            Some(space_id) => format!(
+                "https://{}/wiki/api/v2/spaces/{}/pages?limit={}&{}",
+                api.confluence_domain, space_id, api.page_limit, status
+            ),
+            None => format!(
+                "https://{}/wiki/api/v2/pages?limit={}&{}",
+                api.confluence_domain, api.page_limit, status
+            ),
+        };
+        get_all_results(api, url, &format!("pages of space {}", space_id.unwrap_or("*")))
+    }
+
+    /// Size in bytes of the storage format body of every page in the space.
+    /// Bodies come back with the listing, so this is much heavier than
+    /// get_pages.
+    pub fn get_page_sizes(api: &Api, space_id: &str) -> Result<HashMap<String, usize>> {
+        let pages: Vec<SizedPage> = get_all_results(
+            api,
+            format!(
+                "https://{}/wiki/api/v2/spaces/{}/pages?limit={}&status=current&body-format=storage",
+                api.confluence_domain, space_id, api.page_limit
+            ),
+            &format!("page bodies of space {}", space_id),
+        )?;
+        Ok(pages
+            .into_iter()
+            .map(|page| (page.id, page.body.storage.value.len()))
+            .collect())
+    }
+
+    /// A single page without its body, for when only its title, status or
+    /// version is needed
+    pub fn get_page_summary(api: &Api, id: &str) -> Result<PageSummary> {
+        let resp = send_request(
+            api,
+            RequestType::GET,
+            format!("https://{}/wiki/api/v2/pages/{}", api.confluence_domain, id),
+            &format!("page {}", id),
+        )?;
+        read_json::<PageSummary>(resp)
+    }
+
+    /// Pages titled exactly title, in the given space or in every space the
+    /// user can see
+    pub fn get_pages_by_title(
+        api: &Api,
+        space_id: Option<&str>,
+        title: &str,
+    ) -> Result<Vec<PageSummary>> {
+        let url = match space_id {
+            Some(space_id) => format!(
+                "https://{}/wiki/api/v2/spaces/{}/pages",
+                api.confluence_domain, space_id
+            ),
+            None => format!("https://{}/wiki/api/v2/pages", api.confluence_domain),
+        };
+        let url = reqwest::Url::parse_with_params(&url, &[("title", title)])?;
+        get_all_results(api, url.to_string(), &format!("pages titled \"{}\"", title))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SizedPage {
+    id: String,
+    body: StorageBody,
+}
+
+#[derive(Deserialize, Debug)]
+struct StorageBody {
+    storage: Storage,
+}
+
+/// Any item in the content tree. Spaces hold folders, whiteboards and databases
+/// alongside pages; only pages can be edited but any of them can be a parent.
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(untagged)]
-enum Body {
-    Download(PageBody),
-    Upload(Storage),
+pub struct Content {
+    pub id: String,
+    pub title: String,
+    #[serde(rename = "type")]
+    pub content_type: ContentType,
+    /// Only filled in by [`Content::search`]
+    #[serde(default)]
+    pub space: Option<ContentSpace>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub struct ContentSpace {
+    pub key: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    Page,
+    Folder,
+    Whiteboard,
+    Database,
+    Embed,
+    #[serde(other)]
+    Other,
+}
+
+impl ContentType {
+    fn endpoint(&self) -> &str {
+        match self {
+            ContentType::Folder => "folders",
+            ContentType::Whiteboard => "whiteboards",
+            ContentType::Database => "databases",
+            ContentType::Embed => "embeds",
+            ContentType::Page | ContentType::Other => "pages",
+        }
+    }
+
+    pub fn icon(&self) -> &str {
+        match self {
+            ContentType::Page => "📄",
+            ContentType::Folder => "📁",
+            ContentType::Whiteboard => "🖼",
+            ContentType::Database => "🗃",
+            ContentType::Embed => "🔗",
+            ContentType::Other => "❔",
+        }
+    }
+}
+
+impl Content {
+    /// Runs a CQL search over all content, e.g. "space = ENG and label = runbook"
+    pub fn search(api: &Api, cql: &str) -> Result<Vec<Content>> {
+        let url = reqwest::Url::parse_with_params(
+            &format!(
+                "https://{}/wiki/rest/api/content/search",
+                api.confluence_domain
+            ),
+            &[
+                ("cql", cql),
+                ("expand", "space"),
+                ("limit", &api.page_limit.to_string()),
+            ],
+        )?;
+        get_all_results(api, url.to_string(), "content search")
+    }
+
+    pub fn get_direct_children(&self, api: &Api) -> Result<Vec<Content>> {
+        get_all_results(
+            api,
+            format!(
+                "https://{}/wiki/api/v2/{}/{}/direct-children?limit={}",
+                api.confluence_domain,
+                self.content_type.endpoint(),
+                self.id,
+                api.page_limit
+            ),
+            &format!("children of {}", self.id),
+        )
+    }
+}
+
+/// Quotes a string for use in CQL, escaping any backslashes and quotes in it
+pub fn cql_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Parses the response as it is read rather than buffering it as a string
+// first, which matters for pages with megabytes of storage format
+fn read_json<T: DeserializeOwned>(resp: blocking::Response) -> Result<T> {
+    Ok(serde_json::from_reader(BufReader::new(resp))?)
+}
+
+// Fetches every result of a list endpoint, following the cursor links until
+// there are no more pages of results
+fn get_all_results<T: DeserializeOwned>(
+    api: &Api,
+    url: String,
+    resource: &str,
+) -> Result<Vec<T>> {
+    let mut url = url;
+    let mut all_results = Vec::new();
+    loop {
+        let resp = send_request(api, RequestType::GET, url, resource)?;
+        let mut results = read_json::<Results<T>>(resp)?;
+        all_results.append(&mut results.results);
+        // v1 next links are relative to /wiki, v2 links include it
+        match results.links.and_then(|links| links.next) {
+            Some(next) if next.starts_with("/wiki") => {
+                url = format!("https://{}{}", api.confluence_domain, next)
+            }
+            Some(next) => url = format!("https://{}/wiki{}", api.confluence_domain, next),
+            None => break,
+        }
+    }
+    Ok(all_results)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Task {
+    pub id: String,
+    pub page_id: Option<String>,
+    body: Option<TaskBody>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TaskBody {
+    storage: Storage,
+}
+
+impl Task {
+    pub fn get_body(&self) -> &str {
+        match &self.body {
+            Some(body) => &body.storage.value,
+            None => "",
+        }
+    }
+
+    /// Open tasks in the space, optionally only those assigned to one user
+    pub fn get_open_tasks(api: &Api, space_id: &str, assigned_to: Option<&str>) -> Result<Vec<Task>> {
+        let limit = api.page_limit.to_string();
+        let mut params = vec![
+            ("space-id", space_id),
+            ("status", "incomplete"),
+            ("body-format", "storage"),
+            ("limit", &limit),
+        ];
+        if let Some(account_id) = assigned_to {
+            params.push(("assigned-to", account_id));
+        }
+        let url = reqwest::Url::parse_with_params(
+            &format!("https://{}/wiki/api/v2/tasks", api.confluence_domain),
+            &params,
+        )?;
+        let resource = format!("tasks of space {}", space_id);
+        get_all_results(api, url.to_string(), &resource)
+    }
+
+    pub fn complete(&self, api: &Api) -> Result<()> {
+        let body = serde_json::json!({ "status": "complete" });
+        send_request(
+            api,
+            RequestType::PUT(body.to_string()),
+            format!(
+                "https://{}/wiki/api/v2/tasks/{}",
+                api.confluence_domain, self.id
+            ),
+            &format!("task {}", self.id),
+        )?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub account_id: String,
+    pub display_name: String,
+    pub email: Option<String>,
+    #[serde(rename = "type")]
+    pub user_type: String,
+}
+
+impl User {
+    /// The v2 api has no current user endpoint, so this uses v1
+    pub fn get_current_user(api: &Api) -> Result<User> {
+        let resp = send_request(
+            api,
+            RequestType::GET,
+            format!(
+                "https://{}/wiki/rest/api/user/current",
+                api.confluence_domain
+            ),
+            "current user",
+        )?;
+        read_json::<User>(resp)
+    }
+
+    pub fn get_user(api: &Api, account_id: &str) -> Result<User> {
+        let url = reqwest::Url::parse_with_params(
+            &format!("https://{}/wiki/rest/api/user", api.confluence_domain),
+            &[("accountId", account_id)],
+        )?;
+        let resp = send_request(
+            api,
+            RequestType::GET,
+            url.to_string(),
+            &format!("user {}", account_id),
+        )?;
+        read_json::<User>(resp)
+    }
+
+    /// Finds users whose full name contains the query. Only the first page of
+    /// results is returned: a search vague enough to need more is better
+    /// narrowed down.
+    pub fn search(api: &Api, name: &str) -> Result<Vec<User>> {
+        let cql = format!("type = user and user.fullname ~ {}", cql_string(name));
+        let url = reqwest::Url::parse_with_params(
+            &format!("https://{}/wiki/rest/api/search/user", api.confluence_domain),
+            &[("cql", cql.as_str()), ("limit", &api.page_limit.to_string())],
+        )?;
+        let resp = send_request(
+            api,
+            RequestType::GET,
+            url.to_string(),
+            &format!("users named {}", name),
+        )?;
+        let results = read_json::<Results<UserSearchResult>>(resp)?;
+        Ok(results.results.into_iter().map(|result| result.user).collect())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct UserSearchResult {
+    user: User,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Space {
+    pub id: String,
+    pub key: String,
+    pub name: String,
+    pub homepage_id: Option<String>,
+}
+
+impl Space {
+    pub fn get_spaces(api: &Api) -> Result<Vec<Space>> {
+        get_all_results(
+            api,
+            format!(
+                "https://{}/wiki/api/v2/spaces?limit={}",
+                api.confluence_domain, api.page_limit
+            ),
+            "spaces",
+        )
+    }
+
+    /// Spaces tagged with the given space label
+    pub fn get_spaces_with_label(api: &Api, label: &str) -> Result<Vec<Space>> {
+        let url = reqwest::Url::parse_with_params(
+            &format!("https://{}/wiki/api/v2/spaces", api.confluence_domain),
+            &[("labels", label), ("limit", &api.page_limit.to_string())],
+        )?;
+        get_all_results(api, url.to_string(), "spaces")
+    }
+
+    pub fn get_space_by_key(api: &Api, key: &str) -> Result<Space> {
+        let url = reqwest::Url::parse_with_params(
+            &format!("https://{}/wiki/api/v2/spaces", api.confluence_domain),
+            &[("keys", key)],
+        )?;
+        let resp = send_request(api, RequestType::GET, url.to_string(), &format!("space {}", key))?;
+        read_json::<Results<Space>>(resp)?
+            .results
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No space found with key {}", key))
+    }
+
+    pub fn get_space_by_id(api: &Api, id: &str) -> Result<Space> {
+        let resp = send_request(
+            api,
+            RequestType::GET,
+            format!(
+                "https://{}/wiki/api/v2/spaces/{}",
+                api.confluence_domain, id
+            ),
+            &format!("space {}", id),
+        )?;
+        read_json::<Space>(resp)
+    }
+
+    /// Space ids are always numeric and keys never are, so either can be given
+    pub fn get_space_by_key_or_id(api: &Api, key_or_id: &str) -> Result<Space> {
+        if key_or_id.chars().all(|c| c.is_ascii_digit()) {
+            Space::get_space_by_id(api, key_or_id)
+        } else {
+            Space::get_space_by_key(api, key_or_id)
+        }
+    }
+}
+
+/// Wrapper for the paginated list responses returned by the v2 api
+#[derive(Deserialize, Debug)]
+struct Results<T> {
+    results: Vec<T>,
+    #[serde(rename = "_links")]
+    links: Option<Links>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Links {
+    next: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct NewPage {
+    space_id: String,
+    status: String,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_id: Option<String>,
+    body: Storage,
+}
+
+#[derive(Deserialize, Debug)]
+struct Label {
+    name: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct CreatedPage {
+    id: String,
+}
+
+// A page as Confluence returns it, before its body is read into the shape
+// Page keeps
+#[derive(Deserialize)]
+struct FetchedPage {
+    id: String,
+    title: String,
+    status: String,
+    #[serde(rename = "spaceId")]
+    space_id: String,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+    version: PageVersion,
+    body: PageBody,
+}
+
+// Only the format that was requested is returned
+#[derive(Deserialize)]
 struct PageBody {
-    editor: Storage,
+    editor: Option<Storage>,
+    atlas_doc_format: Option<Storage>,
+}
+
+impl PageBody {
+    // The body labelled with the representation updates expect, as editor
+    // format is sent back as storage
+    fn into_storage(self, resource: &str) -> Result<Storage> {
+        let (value, format) = match (self.editor, self.atlas_doc_format) {
+            (Some(editor), _) => (editor.value, BodyFormat::Storage),
+            (None, Some(adf)) => (adf.value, BodyFormat::Adf),
+            (None, None) => {
+                return Err(ApiError::MissingBody {
+                    resource: resource.to_string(),
+                }
+                .into())
+            }
+        };
+        Ok(Storage {
+            value,
+            representation: format.representation().to_string(),
+        })
+    }
+}
+
+// Reads a page fetched with a body format
+fn read_page(resp: blocking::Response, resource: &str) -> Result<Page> {
+    let fetched = read_json::<FetchedPage>(resp)?;
+    Ok(Page {
+        id: fetched.id,
+        title: fetched.title,
+        status: fetched.status,
+        space_id: fetched.space_id,
+        parent_id: fetched.parent_id,
+        version: fetched.version,
+        body: fetched.body.into_storage(resource)?,
+    })
+}
+
+/// Representation of a page body. Pages made in the new editor are stored as
+/// ADF (Atlassian Document Format) json, others as storage format xhtml.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyFormat {
+    Storage,
+    Adf,
+}
+
+impl BodyFormat {
+    fn representation(&self) -> &str {
+        match self {
+            BodyFormat::Storage => "storage",
+            BodyFormat::Adf => "atlas_doc_format",
+        }
+    }
+
+    // editor is storage format as the editor sees it, which keeps more of the
+    // page than plain storage does
+    fn request_format(&self) -> &str {
+        match self {
+            BodyFormat::Storage => "editor",
+            BodyFormat::Adf => "atlas_doc_format",
+        }
+    }
+}
+
+/// The page's latest version number, without downloading its body. Fetching a
+/// page without a body format leaves the body out, which makes this a cheap way
+/// to check whether a cached copy is still current.
+pub fn get_page_version(api: &Api, id: &str) -> Result<usize> {
+    #[derive(Deserialize)]
+    struct VersionOnly {
+        version: PageVersion,
+    }
+    let resp = send_request(
+        api,
+        RequestType::GET,
+        format!("https://{}/wiki/api/v2/pages/{}", api.confluence_domain, id),
+        &format!("page {}", id),
+    )?;
+    Ok(read_json::<VersionOnly>(resp)?.version.number)
+}
+
+// Page ids are only unique within one Confluence site, so each domain has its
+// own cache
+fn cached_page_path(api: &Api, id: &str) -> Option<PathBuf> {
+    let mut path = home::home_dir()?;
+    path.push(".cache/concmd/pages");
+    path.push(&api.confluence_domain);
+    path.push(format!("{}.json", id));
+    Some(path)
+}
+
+// Listings sit beside the cached pages, whose names are all numeric ids
+fn cached_page_list_path(
+    api: &Api,
+    space_id: Option<&str>,
+    include_archived: bool,
+) -> Option<PathBuf> {
+    let mut path = cached_page_path(api, "list")?;
+    let space = space_id.unwrap_or("all");
+    let archived = if include_archived { "-archived" } else { "" };
+    path.set_file_name(format!("list-{}{}.json", space, archived));
+    Some(path)
+}
+
+/// The listing last saved with [`save_page_list`] for the same space, or for
+/// every space if none is given. Version times aren't kept.
+pub fn read_page_list(
+    api: &Api,
+    space_id: Option<&str>,
+    include_archived: bool,
+) -> Option<Vec<PageSummary>> {
+    let path = cached_page_list_path(api, space_id, include_archived)?;
+    serde_json::from_str(&crate::crypt::read_to_string(path).ok()?).ok()
+}
+
+/// Keeps a listing from [`PageSummary::get_pages`] in the page cache, so it can
+/// be shown straight away next time while a fresh one loads
+pub fn save_page_list(
+    api: &Api,
+    space_id: Option<&str>,
+    include_archived: bool,
+    pages: &[PageSummary],
+) -> Result<()> {
+    let path = cached_page_list_path(api, space_id, include_archived)
+        .context("No home directory to cache pages in")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    crate::crypt::write(path, &serde_json::to_string(pages)?, api.encrypt_cache)
+}
+
+fn read_cached_page(api: &Api, id: &str) -> Option<Page> {
+    let cached = crate::crypt::read_to_string(cached_page_path(api, id)?).ok()?;
+    serde_json::from_str(&cached).ok()
+}
+
+fn write_cached_page(api: &Api, page: &Page) -> Result<()> {
+    let path = cached_page_path(api, &page.id).context("No home directory to cache pages in")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    crate::crypt::write(path, &serde_json::to_string(page)?, api.encrypt_cache)
+}
+
+// Confluence marks pages made in the new editor with an editor property of v2
+fn uses_new_editor(api: &Api, id: &str) -> Result<bool> {
+    let property = ContentProperty::get_property(api, id, "editor")?;
+    Ok(property.is_some_and(|property| property.value == "v2"))
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct PageVersion {
     pub number: usize,
     pub message: Option<String>,
+    #[serde(rename = "createdAt", default, skip_serializing)]
+    pub created_at: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -94,26 +1188,264 @@ struct Storage {
     representation: String,
 }
 
+// Sends the request and turns any non-success status into an ApiError.
+// resource describes what was requested (e.g. "page 12345") for error messages.
 fn send_request(
     api: &Api,
     method: RequestType,
     url: String,
+    resource: &str,
 ) -> Result<blocking::Response> {
-    let client = blocking::Client::new();
-    let generic_client = match method {
-        RequestType::GET => client.get(url),
-        RequestType::PUT(body) => client.put(url).body(body),
+    let client = api.client()?;
+    let idempotent = matches!(method, RequestType::GET | RequestType::DELETE);
+    send_with_retries(api, idempotent, resource, || {
+        let generic_client = match &method {
+            RequestType::GET => client.get(&url),
+            RequestType::PUT(body) => client.put(&url).body(body.clone()),
+            RequestType::POST(body) => client.post(&url).body(body.clone()),
+            RequestType::DELETE => client.delete(&url),
+        };
+        Ok(generic_client
+            .basic_auth(&api.username, Some(&api.token))
+            .header("Content-type", "application/json")
+            .send())
+    })
+}
+
+// Sends body as json with a PUT or POST, like send_request. The json is
+// written into the request as it goes out rather than into a string first, so
+// a page with megabytes of body isn't held in memory twice. A first pass
+// that keeps nothing works out its length, as Confluence wants one up front.
+fn send_json<T: Serialize + Sync>(
+    api: &Api,
+    method: Method,
+    url: String,
+    resource: &str,
+    body: &T,
+) -> Result<blocking::Response> {
+    let client = api.client()?;
+    let mut length = ByteCount(0);
+    serde_json::to_writer(&mut length, body)?;
+    send_with_retries(api, false, resource, || {
+        thread::scope(|scope| {
+            let (reader, writer) = io::pipe()?;
+            let writing = scope.spawn(move || {
+                let mut writer = BufWriter::new(writer);
+                serde_json::to_writer(&mut writer, body)?;
+                writer.flush().map_err(serde_json::Error::io)
+            });
+            let result = client
+                .request(method.clone(), &url)
+                .basic_auth(&api.username, Some(&api.token))
+                .header("Content-type", "application/json")
+                .body(blocking::Body::sized(reader, length.0))
+                .send();
+            let written = writing.join().expect("serialising shouldn't panic");
+            match written {
+                // The pipe is closed early when the request fails part way
+                Err(e) if !e.is_io() => Err(e.into()),
+                _ => Ok(result),
+            }
+        })
+    })
+}
+
+// A writer that only counts what is written to it
+struct ByteCount(u64);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        io::Result::Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Result::Ok(())
+    }
+}
+
+// Makes a request with send, which returns an error for anything that went
+// wrong before the request could be sent. Connection failures and rate
+// limiting are retried up to max_retries times with exponential backoff,
+// capped at 64s. Timeouts and server errors are only retried for idempotent
+// requests (GET and DELETE), as a POST or PUT may already have been carried
+// out and sending it again could create a page or comment twice.
+fn send_with_retries(
+    api: &Api,
+    idempotent: bool,
+    resource: &str,
+    mut send: impl FnMut() -> Result<reqwest::Result<blocking::Response>>,
+) -> Result<blocking::Response> {
+    let mut attempt = 0;
+    loop {
+        let result = send()?;
+        let retryable = match &result {
+            Result::Ok(resp) => {
+                resp.status() == 429 || (idempotent && resp.status().is_server_error())
+            }
+            Err(e) => e.is_connect() || (idempotent && e.is_timeout()),
+        };
+        if retryable && attempt < api.max_retries {
+            thread::sleep(Duration::from_secs(1 << attempt.min(6)));
+            attempt += 1;
+            continue;
+        }
+        let resp = match result {
+            Err(e) if e.is_timeout() => {
+                return Err(ApiError::Timeout {
+                    resource: resource.to_string(),
+                    seconds: api.request_timeout,
+                }
+                .into())
+            }
+            result => result?,
+        };
+        return if resp.status().is_success() {
+            Ok(resp)
+        } else {
+            Err(ApiError::from_response(api, resp, resource).into())
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    Unauthorized {
+        domain: String,
+    },
+    Forbidden {
+        resource: String,
+        detail: String,
+    },
+    NotFound {
+        resource: String,
+        path: String,
+        detail: String,
+    },
+    Conflict {
+        resource: String,
+        detail: String,
+    },
+    RateLimited {
+        domain: String,
+    },
+    Timeout {
+        resource: String,
+        seconds: u64,
+    },
+    MissingBody {
+        resource: String,
+    },
+    Other {
+        status: u16,
+        resource: String,
+        path: String,
+        detail: String,
+    },
+}
+
+impl ApiError {
+    fn from_response(api: &Api, resp: blocking::Response, resource: &str) -> ApiError {
+        let status = resp.status().as_u16();
+        let path = resp.url().path().to_string();
+        let detail = error_detail(&resp.text().unwrap_or_default());
+        let resource = resource.to_string();
+        match status {
+            401 => ApiError::Unauthorized {
+                domain: api.confluence_domain.clone(),
+            },
+            403 => ApiError::Forbidden { resource, detail },
+            404 => ApiError::NotFound {
+                resource,
+                path,
+                detail,
+            },
+            409 => ApiError::Conflict { resource, detail },
+            429 => ApiError::RateLimited {
+                domain: api.confluence_domain.clone(),
+            },
+            _ => ApiError::Other {
+                status,
+                resource,
+                path,
+                detail,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Unauthorized { domain } => write!(
+                f,
+                "Authentication failed for {} — check username/token",
+                domain
+            ),
+            ApiError::Forbidden { resource, detail } => {
+                write!(f, "Permission denied for {}{}", resource, detail)
+            }
+            ApiError::NotFound {
+                resource,
+                path,
+                detail,
+            } => write!(f, "Could not find {} ({}){}", resource, path, detail),
+            ApiError::Conflict { resource, detail } => write!(
+                f,
+                "Conflict updating {}, it may have been changed by someone else{}",
+                resource, detail
+            ),
+            ApiError::RateLimited { domain } => {
+                write!(f, "Rate limited by {} — wait a moment and retry", domain)
+            }
+            ApiError::Timeout { resource, seconds } => write!(
+                f,
+                "Request for {} timed out after {}s — retry, or allow longer with --timeout",
+                resource, seconds
+            ),
+            ApiError::MissingBody { resource } => {
+                write!(f, "Confluence returned {} without its body", resource)
+            }
+            ApiError::Other {
+                status,
+                resource,
+                path,
+                detail,
+            } => write!(
+                f,
+                "Request for {} failed with status {} ({}){}",
+                resource, status, path, detail
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// Pulls the human readable part out of a Confluence error body, formatted to
+// be appended to an error message. v2 returns a list of errors with a title and
+// detail, v1 returns a single message.
+fn error_detail(body: &str) -> String {
+    let Some(json) = serde_json::from_str::<serde_json::Value>(body).ok() else {
+        return String::new();
     };
-    let resp = generic_client
-        .basic_auth(&api.username, Some(&api.token))
-        .header("Content-type", "application/json")
-        .send()?;
-    Ok(resp)
+    let detail = json["errors"][0]["detail"]
+        .as_str()
+        .or_else(|| json["errors"][0]["title"].as_str())
+        .or_else(|| json["message"].as_str());
+    match detail {
+        Some(detail) => format!(": {}", detail),
+        None => String::new(),
+    }
 }
 
+// Named after the HTTP methods they send
+#[allow(clippy::upper_case_acronyms)]
 enum RequestType {
     GET,
     PUT(String),
+    POST(String),
+    DELETE,
 }
 
 impl fmt::Display for RequestType {
@@ -121,6 +1453,8 @@ impl fmt::Display for RequestType {
         match *self {
             RequestType::GET => write!(f, "GET"),
             RequestType::PUT(_) => write!(f, "PUT"),
+            RequestType::POST(_) => write!(f, "POST"),
+            RequestType::DELETE => write!(f, "DELETE"),
         }
     }
 }
@@ -0,0 +1,202 @@
+use anyhow::{Context, Result};
+use reqwest::blocking;
+use serde::{de::Error, Deserialize, Deserializer};
+use std::fs::File;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// Config structure, read from the user's config.toml. Note deserialize_with
+/// for save_location, see fn
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    #[serde(deserialize_with = "from_tilde_path")]
+    pub save_location: PathBuf,
+    #[serde(default = "default_filename_pattern")]
+    pub filename_pattern: String,
+    #[serde(default)]
+    pub auto_clean: bool,
+    #[serde(default)]
+    pub auto_sync: AutoSync,
+    #[serde(default)]
+    pub save_mode: SaveMode,
+    #[serde(default)]
+    pub legacy_editor: LegacyEditor,
+    /// Pages export fetches and converts at once
+    #[serde(default = "default_export_jobs")]
+    pub export_jobs: usize,
+    /// Title for new and uploaded pages that aren't given one, e.g.
+    /// "{filename} — {date}"
+    pub title_template: Option<String>,
+    /// Encrypt saved pages and the page cache, see [`crate::crypt`]
+    #[serde(default)]
+    pub encrypt_files: bool,
+    pub api: Api,
+    pub jira: Option<Jira>,
+    #[serde(default)]
+    pub confirm: Confirm,
+}
+
+impl Config {
+    pub fn read_config<P: AsRef<Path>>(file_name: &P) -> Result<Config> {
+        let mut contents = String::new();
+        let mut file = File::open(file_name).context("Config file could not be found")?;
+        file.read_to_string(&mut contents)
+            .context("File is not readable")?;
+        let mut config = toml::from_str::<Config>(contents.as_str())
+            .context("The config file could not be parsed: check the formatting")?;
+        config.api.encrypt_cache = config.encrypt_files;
+        Ok(config)
+    }
+}
+
+/// Whether a page is published after its editor closes
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoSync {
+    /// Publish without asking, unless the page has been updated on Confluence
+    /// since it was downloaded and confirm.overwrite is on
+    Always,
+    Never,
+    /// Ask, and ask again before publishing over changes made on Confluence
+    /// since the page was downloaded
+    #[default]
+    Prompt,
+    /// Publish without asking unless someone else has updated the page since
+    /// it was downloaded, in which case ask as for prompt
+    IfUnchangedRemote,
+}
+
+/// Where downloaded pages are written for editing
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SaveMode {
+    /// Keep pages in save_location
+    #[default]
+    Directory,
+    /// Write pages to a private temp directory for the run and delete each
+    /// one once it is published, so nothing is left behind on shared machines
+    Temp,
+}
+
+/// What edit does with pages made in the legacy editor, whose storage format
+/// can lose formatting when converted to markdown and back
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LegacyEditor {
+    /// Edit them after printing a warning
+    #[default]
+    Warn,
+    /// Only edit them when given --force
+    Refuse,
+}
+
+/// Which operations ask before going ahead. Those set to false go ahead
+/// without asking; all of them ask by default.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Confirm {
+    /// Publishing an edited page with auto_sync = 'prompt', and undo
+    pub publish: bool,
+    /// Publishing over changes made on Confluence since the page was
+    /// downloaded, or publishing a page that was emptied
+    pub overwrite: bool,
+    /// Moving a page to the trash or deleting it for good
+    pub delete: bool,
+    /// Ask for the page title to be typed out, rather than y/n, before
+    /// deleting a page for good
+    pub type_title_to_purge: bool,
+    /// Applying a plan, and each page updated by replace
+    pub bulk: bool,
+}
+
+impl Default for Confirm {
+    fn default() -> Confirm {
+        Confirm {
+            publish: true,
+            overwrite: true,
+            delete: true,
+            type_title_to_purge: true,
+            bulk: true,
+        }
+    }
+}
+
+/// Connection details for a Confluence Cloud site, passed to every
+/// [`crate::conf_api`] call. Also holds the http client, so every request made
+/// with the same Api, or a clone of it, reuses its connections.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Api {
+    pub confluence_domain: String,
+    pub username: String,
+    pub token: String,
+    #[serde(default = "default_page_limit")]
+    pub page_limit: usize,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    #[serde(default)]
+    pub max_retries: u32,
+    /// Keep fetched pages in ~/.cache/concmd and only download a page's body
+    /// again when its version has changed. pick's page titles are kept there
+    /// too.
+    #[serde(default)]
+    pub cache_pages: bool,
+    // Copied from Config::encrypt_files, as the cache is written from conf_api
+    #[serde(skip)]
+    pub(crate) encrypt_cache: bool,
+    #[serde(skip)]
+    client: OnceLock<blocking::Client>,
+}
+
+impl Api {
+    // Built on first use, so commands that never make a request don't pay for
+    // setting up TLS
+    pub(crate) fn client(&self) -> Result<&blocking::Client> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = blocking::Client::builder()
+            .timeout(Duration::from_secs(self.request_timeout))
+            .build()?;
+        Ok(self.client.get_or_init(|| client))
+    }
+}
+
+/// Jira instance linked to Confluence, used to convert jira:KEY-123 shortcodes
+/// and issue links to and from the Jira issue macro
+#[derive(Deserialize, Debug)]
+pub struct Jira {
+    pub domain: String,
+    pub server_id: Option<String>,
+}
+
+// Saved pages are named after the title, with the id to keep names unique
+fn default_filename_pattern() -> String {
+    "{slug}-{id}".to_string()
+}
+
+fn default_export_jobs() -> usize {
+    4
+}
+
+// Confluence caps list requests at 250 results per page
+fn default_page_limit() -> usize {
+    250
+}
+
+fn default_request_timeout() -> u64 {
+    30
+}
+
+// Implements a custom deserializer for save_location that automatically
+// expands the tilde to the users home directory (unix only)
+fn from_tilde_path<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    expanduser::expanduser(s).map_err(D::Error::custom)
+}
@@ -0,0 +1,852 @@
+//! Conversion between Confluence storage format and markdown. Confluence
+//! specific elements that the generic converters don't understand are turned
+//! into plain html before converting to markdown, and turned back into their
+//! storage form after rendering the markdown.
+//!
+//! Anything else in the ac: or ri: namespaces (macros, links, images...) is
+//! protected: it is swapped for a placeholder while converting and put back
+//! verbatim afterwards, so it survives the round trip as raw xml in the
+//! markdown file.
+//!
+//! The config is optional so that conversion still works without a config file,
+//! but some conversions (e.g. jira links) need settings from it.
+
+use anyhow::Result;
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+use regex::Regex;
+use std::ops::Range;
+
+use crate::adf;
+use crate::conf_api::BodyFormat;
+use crate::{Config, Jira};
+
+/// Converts a page body in either format to markdown
+pub fn body_to_markdown(body: &str, format: BodyFormat, config: Option<&Config>) -> Result<String> {
+    match format {
+        BodyFormat::Storage => Ok(to_markdown(body, config)),
+        BodyFormat::Adf => adf::to_markdown(body),
+    }
+}
+
+/// Converts markdown to a page body in the given format
+pub fn markdown_to_body(markdown: &str, format: BodyFormat, config: Option<&Config>) -> Result<String> {
+    match format {
+        BodyFormat::Storage => Ok(to_storage(markdown, config)),
+        BodyFormat::Adf => adf::from_markdown(markdown),
+    }
+}
+
+/// Converts a page body from Confluence storage format to markdown
+pub fn to_markdown(storage: &str, config: Option<&Config>) -> String {
+    let storage = tasks_to_html(storage);
+    let jira = config.and_then(|config| config.jira.as_ref());
+    let storage = jira_macros_to_links(&storage, jira);
+    let storage = expand_macros_to_details(&storage);
+    let storage = panel_macros_to_alerts(&storage);
+    let (storage, protected) = protect(&storage);
+    // After restoring, so html2md doesn't escape the underscores in shortcodes
+    let markdown = emoticons_to_shortcodes(&restore(&html2md::parse_html(&storage), &protected));
+    let markdown = toc_macros_to_markers(&widgets_to_shortcodes(&markdown));
+    tidy_alerts(&separate_details(&markdown))
+}
+
+/// Converts markdown to Confluence storage format, ready to upload as a page body
+pub fn to_storage(markdown: &str, config: Option<&Config>) -> String {
+    let jira = config.and_then(|config| config.jira.as_ref());
+    let markdown = jira_links_to_macros(markdown, jira);
+    let markdown = shortcodes_to_emoticons(&markdown);
+    let markdown = shortcodes_to_widgets(&markdown);
+    let markdown = markers_to_toc_macros(&markdown);
+    let (markdown, protected) = protect(&markdown);
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_GFM);
+    let mut storage = String::new();
+    html::push_html(&mut storage, Parser::new_ext(&markdown, options));
+    let storage = alerts_to_panel_macros(&details_to_expand_macros(&html_to_tasks(&storage)));
+    restore(&storage, &protected)
+}
+
+/// Returns the text of each block in the markdown (paragraphs, headings, list
+/// items, table cells and code block lines) with the formatting stripped and
+/// whitespace collapsed. Comparing these shows whether content was lost in a
+/// round trip through Confluence, which reformats the markdown. Raw xml is
+/// left out, so protected macros aren't compared.
+pub fn text_blocks(markdown: &str) -> Vec<String> {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    let mut blocks = Vec::new();
+    let mut current = String::new();
+    let mut flush = |current: &mut String| {
+        let text = current.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            blocks.push(text);
+        }
+        current.clear();
+    };
+    let mut in_code_block = false;
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Text(text) if in_code_block => {
+                for line in text.lines() {
+                    current.push_str(line);
+                    flush(&mut current);
+                }
+            }
+            Event::Text(text) | Event::Code(text) => current.push_str(&text),
+            Event::SoftBreak | Event::HardBreak => current.push(' '),
+            Event::TaskListMarker(checked) => {
+                current.push_str(if checked { "[x] " } else { "[ ] " })
+            }
+            Event::Start(
+                Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link { .. } | Tag::Image { .. },
+            )
+            | Event::End(
+                TagEnd::Emphasis | TagEnd::Strong | TagEnd::Strikethrough | TagEnd::Link | TagEnd::Image,
+            ) => (),
+            Event::Start(tag) => {
+                in_code_block = matches!(tag, Tag::CodeBlock(_));
+                flush(&mut current);
+            }
+            Event::End(_) => {
+                in_code_block = false;
+                flush(&mut current);
+            }
+            _ => (),
+        }
+    }
+    flush(&mut current);
+    blocks
+}
+
+/// A `[TOC]` marker on a line of its own, standing for the table of contents
+/// macro. The depth and style can be given as `[TOC depth=3 style=none]`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Toc {
+    pub(crate) depth: Option<String>,
+    pub(crate) style: Option<String>,
+}
+
+impl Toc {
+    pub(crate) fn parse(line: &str) -> Option<Toc> {
+        let options = line.trim().strip_prefix("[TOC")?.strip_suffix(']')?;
+        if !options.is_empty() && !options.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let mut toc = Toc::default();
+        for option in options.split_whitespace() {
+            let (name, value) = option.split_once('=')?;
+            if value.is_empty() || !value.chars().all(char::is_alphanumeric) {
+                return None;
+            }
+            match name {
+                "depth" => toc.depth = Some(value.to_string()),
+                "style" => toc.style = Some(value.to_string()),
+                _ => return None,
+            }
+        }
+        Some(toc)
+    }
+
+    // Returns None if the macro has parameters the marker can't hold
+    pub(crate) fn from_parameters<'a>(
+        parameters: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Option<Toc> {
+        let mut toc = Toc::default();
+        for (name, value) in parameters {
+            if value.is_empty() || !value.chars().all(char::is_alphanumeric) {
+                return None;
+            }
+            match name {
+                "maxLevel" => toc.depth = Some(value.to_string()),
+                "style" => toc.style = Some(value.to_string()),
+                _ => return None,
+            }
+        }
+        Some(toc)
+    }
+
+    pub(crate) fn parameters(&self) -> Vec<(&str, &str)> {
+        let mut parameters = Vec::new();
+        if let Some(depth) = &self.depth {
+            parameters.push(("maxLevel", depth.as_str()));
+        }
+        if let Some(style) = &self.style {
+            parameters.push(("style", style.as_str()));
+        }
+        parameters
+    }
+
+    pub(crate) fn marker(&self) -> String {
+        let mut marker = "[TOC".to_string();
+        if let Some(depth) = &self.depth {
+            marker.push_str(&format!(" depth={}", depth));
+        }
+        if let Some(style) = &self.style {
+            marker.push_str(&format!(" style={}", style));
+        }
+        marker.push(']');
+        marker
+    }
+}
+
+// Placeholders are plain alphanumerics so neither converter will escape or
+// reformat them
+fn placeholder(index: usize) -> String {
+    format!("CONCMDPROTECTED{}X", index)
+}
+
+// Replaces every top level ac:/ri: element with a placeholder, returning the
+// replaced text and the original elements in placeholder order
+fn protect(text: &str) -> (String, Vec<String>) {
+    let mut protected = Vec::new();
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = find_element_start(rest) {
+        output.push_str(&rest[..start]);
+        let element_len = element_length(&rest[start..]);
+        protected.push(rest[start..start + element_len].to_string());
+        output.push_str(&placeholder(protected.len() - 1));
+        rest = &rest[start + element_len..];
+    }
+    output.push_str(rest);
+    (output, protected)
+}
+
+fn restore(text: &str, protected: &[String]) -> String {
+    let mut text = text.to_string();
+    for (i, element) in protected.iter().enumerate() {
+        let placeholder = placeholder(i);
+        // Block level elements end up wrapped in a paragraph of their own
+        text = text
+            .replace(&format!("<p>{}</p>", placeholder), element)
+            .replace(&placeholder, element);
+    }
+    text
+}
+
+// Dates are plain <time> elements rather than ac: ones, but html2md would drop
+// them just the same
+fn find_element_start(text: &str) -> Option<usize> {
+    ["<ac:", "<ri:", "<time "]
+        .iter()
+        .filter_map(|start| text.find(start))
+        .min()
+}
+
+// Length of the element at the start of text, including any nested elements
+// with the same name. Unclosed elements run to the end of the text.
+fn element_length(text: &str) -> usize {
+    let name_end = text[1..]
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .map_or(text.len(), |i| i + 1);
+    let name = &text[1..name_end];
+    let open_tag = format!("<{}", name);
+    let close_tag = format!("</{}>", name);
+
+    let mut depth = 0;
+    let mut position = 0;
+    while position < text.len() {
+        let rest = &text[position..];
+        if rest.starts_with(&close_tag) {
+            depth -= 1;
+            position += close_tag.len();
+            if depth == 0 {
+                return position;
+            }
+        } else if rest.starts_with(&open_tag)
+            && rest[open_tag.len()..]
+                .starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        {
+            let tag_end = rest.find('>').map_or(rest.len(), |i| i + 1);
+            position += tag_end;
+            if !rest[..tag_end].ends_with("/>") {
+                depth += 1;
+            } else if depth == 0 {
+                return position;
+            }
+        } else {
+            position += rest.chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    text.len()
+}
+
+// <ac:task-list> -> "- [ ]" list items
+fn tasks_to_html(storage: &str) -> String {
+    let task_regex = Regex::new(
+        r"(?s)<ac:task>.*?<ac:task-status>(\w+)</ac:task-status>.*?<ac:task-body>(.*?)</ac:task-body>.*?</ac:task>",
+    )
+    .expect("regex should always compile");
+    let storage = task_regex.replace_all(storage, |caps: &regex::Captures| {
+        let checkbox = if &caps[1] == "complete" { "[x]" } else { "[ ]" };
+        format!("<li>{} {}</li>", checkbox, &caps[2])
+    });
+    storage
+        .replace("<ac:task-list>", "<ul>")
+        .replace("</ac:task-list>", "</ul>")
+}
+
+// Checkbox list items rendered from markdown -> <ac:task-list>
+fn html_to_tasks(storage: &str) -> String {
+    let checkbox_regex =
+        Regex::new(r#"(?s)<li><input disabled="" type="checkbox"( checked="")?/>\s*(.*?)</li>"#)
+            .expect("regex should always compile");
+    let storage = checkbox_regex.replace_all(storage, |caps: &regex::Captures| {
+        let status = if caps.get(1).is_some() {
+            "complete"
+        } else {
+            "incomplete"
+        };
+        format!(
+            "<ac:task><ac:task-status>{}</ac:task-status><ac:task-body>{}</ac:task-body></ac:task>",
+            status, &caps[2]
+        )
+    });
+    storage
+        .replace("<ul>\n<ac:task>", "<ac:task-list>\n<ac:task>")
+        .replace("</ac:task>\n</ul>", "</ac:task>\n</ac:task-list>")
+}
+
+// Expand macros -> <details>, which html2md keeps as html while converting
+// the contents. Works from the outside in so nested expands stay nested.
+fn expand_macros_to_details(storage: &str) -> String {
+    let title_regex =
+        Regex::new(r#"(?s)^[^>]*>\s*<ac:parameter ac:name="title">(.*?)</ac:parameter>"#)
+            .expect("regex should always compile");
+    replace_elements(storage, r#"<ac:structured-macro ac:name="expand""#, |element| {
+        let title = title_regex
+            .captures(element)
+            .map_or(String::new(), |caps| caps[1].to_string());
+        format!(
+            "<details><summary>{}</summary>{}</details>",
+            title,
+            expand_macros_to_details(rich_text_body(element))
+        )
+    })
+}
+
+// Replaces each element starting with start, including everything nested in
+// it, with the result of replace
+fn replace_elements(text: &str, start: &str, replace: impl Fn(&str) -> String) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(element_start) = rest.find(start) {
+        output.push_str(&rest[..element_start]);
+        let element_len = element_length(&rest[element_start..]);
+        output.push_str(&replace(&rest[element_start..element_start + element_len]));
+        rest = &rest[element_start + element_len..];
+    }
+    output.push_str(rest);
+    output
+}
+
+fn rich_text_body(element: &str) -> &str {
+    element
+        .split_once("<ac:rich-text-body>")
+        .and_then(|(_, body)| body.rsplit_once("</ac:rich-text-body>"))
+        .map_or("", |(body, _)| body)
+}
+
+// Alert kinds and the panel macros they map to. There is no purple panel in
+// storage format, so important alerts come back as notes.
+const ALERT_PANELS: [(&str, &str); 5] = [
+    ("NOTE", "info"),
+    ("TIP", "tip"),
+    ("WARNING", "note"),
+    ("CAUTION", "warning"),
+    ("IMPORTANT", "info"),
+];
+
+// Info, tip, note and warning macros -> a quote starting with a GitHub style
+// alert marker, e.g. > [!NOTE]
+fn panel_macros_to_alerts(storage: &str) -> String {
+    let mut storage = storage.to_string();
+    // The first alert for each panel is the one it converts back to
+    for (kind, panel) in &ALERT_PANELS[..4] {
+        let start = format!("<ac:structured-macro ac:name=\"{}\"", panel);
+        storage = replace_elements(&storage, &start, |element| {
+            format!(
+                "<blockquote><p>[!{}]</p>{}</blockquote>",
+                kind,
+                panel_macros_to_alerts(rich_text_body(element))
+            )
+        });
+    }
+    storage
+}
+
+// html2md pads quotes with empty lines, but the alert marker only counts on
+// the first line of the quote
+fn tidy_alerts(markdown: &str) -> String {
+    let marker_regex = Regex::new(
+        r"(?m)^(?:>[ \t]*\n)*> \[!(NOTE|TIP|IMPORTANT|WARNING|CAUTION)\][ \t]*\n(?:>[ \t]*\n)*",
+    )
+    .expect("regex should always compile");
+    marker_regex.replace_all(markdown, "> [!$1]\n").to_string()
+}
+
+// Alert quotes rendered from markdown -> panel macros
+fn alerts_to_panel_macros(storage: &str) -> String {
+    replace_elements(storage, "<blockquote class=\"markdown-alert-", |element| {
+        let (open_tag, body) = element.split_once('>').unwrap_or((element, ""));
+        let body = body.strip_suffix("</blockquote>").unwrap_or(body);
+        let panel = ALERT_PANELS
+            .iter()
+            .find(|(kind, _)| open_tag.ends_with(&format!("-{}\"", kind.to_lowercase())))
+            .map_or("info", |(_, panel)| panel);
+        format!(
+            "<ac:structured-macro ac:name=\"{}\" ac:schema-version=\"1\"><ac:rich-text-body>{}</ac:rich-text-body></ac:structured-macro>",
+            panel,
+            alerts_to_panel_macros(body)
+        )
+    })
+}
+
+// html2md writes the contents straight after the tags, but markdown inside a
+// html block is only parsed as markdown with blank lines around it
+fn separate_details(markdown: &str) -> String {
+    let open_regex = Regex::new(r"(?s)\n*<details>\s*<summary>(.*?)</summary>\s*")
+        .expect("regex should always compile");
+    let close_regex = Regex::new(r"\s*</details>").expect("regex should always compile");
+    let after_close_regex = Regex::new(r"</details>\n*").expect("regex should always compile");
+    let markdown = open_regex.replace_all(markdown, "\n\n<details>\n<summary>$1</summary>\n\n");
+    let markdown = close_regex.replace_all(&markdown, "\n\n</details>");
+    after_close_regex
+        .replace_all(&markdown, "</details>\n\n")
+        .trim_matches('\n')
+        .to_string()
+}
+
+// <details> html blocks rendered from markdown -> expand macros
+fn details_to_expand_macros(storage: &str) -> String {
+    let open_regex = Regex::new(r"(?s)<details>\s*(?:<summary>(.*?)</summary>)?")
+        .expect("regex should always compile");
+    let storage = open_regex.replace_all(storage, |caps: &regex::Captures| {
+        let title = match caps.get(1).map(|title| title.as_str().trim()) {
+            Some(title) if !title.is_empty() => {
+                format!("<ac:parameter ac:name=\"title\">{}</ac:parameter>", title)
+            }
+            _ => String::new(),
+        };
+        format!(
+            "<ac:structured-macro ac:name=\"expand\" ac:schema-version=\"1\">{}<ac:rich-text-body>",
+            title
+        )
+    });
+    storage.replace("</details>", "</ac:rich-text-body></ac:structured-macro>")
+}
+
+// Jira issue macro -> a link to the issue if the jira domain is configured,
+// otherwise a jira:KEY-123 shortcode
+fn jira_macros_to_links(storage: &str, jira: Option<&Jira>) -> String {
+    let macro_regex = Regex::new(
+        r#"(?s)<ac:structured-macro ac:name="jira"[^>]*>.*?<ac:parameter ac:name="key">([A-Z][A-Z0-9_]*-\d+)</ac:parameter>.*?</ac:structured-macro>"#,
+    )
+    .expect("regex should always compile");
+    macro_regex
+        .replace_all(storage, |caps: &regex::Captures| match jira {
+            Some(jira) => format!(
+                "<a href=\"https://{}/browse/{}\">{}</a>",
+                jira.domain, &caps[1], &caps[1]
+            ),
+            None => format!("jira:{}", &caps[1]),
+        })
+        .to_string()
+}
+
+// jira:KEY-123 shortcodes, and links to issues on the configured jira domain
+// -> Jira issue macro, outside of code
+fn jira_links_to_macros(markdown: &str, jira: Option<&Jira>) -> String {
+    let shortcode_regex =
+        Regex::new(r"\bjira:([A-Z][A-Z0-9_]*-\d+)\b").expect("regex should always compile");
+    let mut markdown =
+        replace_outside_code(&shortcode_regex, markdown, |caps| jira_macro(&caps[1], jira));
+    if let Some(jira) = jira {
+        let link_regex = Regex::new(&format!(
+            r"\[([A-Z][A-Z0-9_]*-\d+)\]\(https?://{}/browse/([A-Z][A-Z0-9_]*-\d+)\)",
+            regex::escape(&jira.domain)
+        ))
+        .expect("regex should always compile");
+        markdown = replace_outside_code(&link_regex, &markdown, |caps| {
+            // Only links whose text is the issue key, so that other
+            // links to jira keep their text
+            if caps[1] == caps[2] {
+                jira_macro(&caps[2], Some(jira))
+            } else {
+                caps[0].to_string()
+            }
+        });
+    }
+    markdown
+}
+
+fn jira_macro(key: &str, jira: Option<&Jira>) -> String {
+    let server = match jira.and_then(|jira| jira.server_id.as_ref()) {
+        Some(server_id) => format!(
+            "<ac:parameter ac:name=\"server\">System Jira</ac:parameter><ac:parameter ac:name=\"serverId\">{}</ac:parameter>",
+            server_id
+        ),
+        None => String::new(),
+    };
+    format!(
+        "<ac:structured-macro ac:name=\"jira\" ac:schema-version=\"1\">{}<ac:parameter ac:name=\"key\">{}</ac:parameter></ac:structured-macro>",
+        server, key
+    )
+}
+
+// Emoticons Confluence can render, as (shortcode, legacy emoticon name, emoji
+// id, fallback character). The legacy names cover the original emoticon set.
+const EMOTICONS: &[(&str, &str, &str, &str)] = &[
+    (":smile:", "smile", "1f642", "🙂"),
+    (":disappointed:", "sad", "1f641", "🙁"),
+    (":stuck_out_tongue:", "cheeky", "1f61b", "😛"),
+    (":laughing:", "laugh", "1f600", "😀"),
+    (":wink:", "wink", "1f609", "😉"),
+    (":thumbsup:", "thumbs-up", "1f44d", "👍"),
+    (":thumbsdown:", "thumbs-down", "1f44e", "👎"),
+    (":info:", "information", "atlassian-info", "ℹ️"),
+    (":check_mark:", "tick", "atlassian-check_mark", "✅"),
+    (":cross_mark:", "cross", "atlassian-cross_mark", "❌"),
+    (":warning:", "warning", "atlassian-warning", "⚠️"),
+    (":plus:", "plus", "atlassian-plus", "➕"),
+    (":minus:", "minus", "atlassian-minus", "➖"),
+    (":question:", "question", "atlassian-question_mark", "❓"),
+    (
+        ":light_bulb_on:",
+        "light-on",
+        "atlassian-light_bulb_on",
+        "💡",
+    ),
+    (
+        ":light_bulb_off:",
+        "light-off",
+        "atlassian-light_bulb_off",
+        "💡",
+    ),
+    (
+        ":yellow_star:",
+        "yellow-star",
+        "atlassian-yellow_star",
+        "⭐",
+    ),
+    (":red_star:", "red-star", "atlassian-red_star", "⭐"),
+    (":green_star:", "green-star", "atlassian-green_star", "⭐"),
+    (":blue_star:", "blue-star", "atlassian-blue_star", "⭐"),
+    (":heart:", "heart", "2764", "❤️"),
+    (":broken_heart:", "broken-heart", "1f494", "💔"),
+];
+
+// <ac:emoticon> -> :shortcode:. Emoji without a known shortcode fall back to
+// the emoji character itself so they still survive the round trip as text.
+fn emoticons_to_shortcodes(storage: &str) -> String {
+    let emoticon_regex =
+        Regex::new(r"<ac:emoticon\s([^>]*?)/?>").expect("regex should always compile");
+    let attribute_regex =
+        Regex::new(r#"([\w:-]+)="([^"]*)""#).expect("regex should always compile");
+    emoticon_regex
+        .replace_all(storage, |caps: &regex::Captures| {
+            let attribute = |name: &str| {
+                attribute_regex
+                    .captures_iter(&caps[1])
+                    .find(|attr| &attr[1] == name)
+                    .map(|attr| attr[2].to_string())
+            };
+            let shortname = attribute("ac:emoji-shortname");
+            let name = attribute("ac:name").unwrap_or_default();
+            let known = EMOTICONS
+                .iter()
+                .find(|(shortcode, legacy_name, _, _)| match &shortname {
+                    Some(shortname) => shortcode == shortname,
+                    None => *legacy_name == name,
+                });
+            match (known, attribute("ac:emoji-fallback")) {
+                (Some((shortcode, _, _, _)), _) => shortcode.to_string(),
+                (None, Some(fallback)) => fallback,
+                (None, None) => shortname.unwrap_or(format!(":{}:", name)),
+            }
+        })
+        .to_string()
+}
+
+// Known :shortcode: -> <ac:emoticon>, outside of code
+fn shortcodes_to_emoticons(markdown: &str) -> String {
+    let shortcode_regex = Regex::new(r":[a-z_]+:").expect("regex should always compile");
+    replace_outside_code(&shortcode_regex, markdown, |caps| {
+        match EMOTICONS.iter().find(|(shortcode, _, _, _)| *shortcode == &caps[0]) {
+            Some((shortcode, name, id, fallback)) => format!(
+                "<ac:emoticon ac:name=\"{}\" ac:emoji-shortname=\"{}\" ac:emoji-id=\"{}\" ac:emoji-fallback=\"{}\"/>",
+                name, shortcode, id, fallback
+            ),
+            None => caps[0].to_string(),
+        }
+    })
+}
+
+// Regex::replace_all, except that matches starting inside a code span or block
+// are left as they are, so code is published exactly as written
+fn replace_outside_code(
+    regex: &Regex,
+    markdown: &str,
+    mut replacement: impl FnMut(&regex::Captures) -> String,
+) -> String {
+    let code: Vec<Range<usize>> = Parser::new(markdown)
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Code(_) | Event::Start(Tag::CodeBlock(_))))
+        .map(|(_, range)| range)
+        .collect();
+    regex
+        .replace_all(markdown, |caps: &regex::Captures| {
+            let start = caps.get(0).expect("group 0 is the whole match").start();
+            match code.iter().any(|range| range.contains(&start)) {
+                true => caps[0].to_string(),
+                false => replacement(caps),
+            }
+        })
+        .to_string()
+}
+
+// Status lozenge macros -> {status:colour|Title}, and dates -> {date:2025-01-01}
+fn widgets_to_shortcodes(markdown: &str) -> String {
+    let status_regex =
+        Regex::new(r#"(?s)<ac:structured-macro ac:name="status"[^>]*>(.*?)</ac:structured-macro>"#)
+            .expect("regex should always compile");
+    let parameter_regex = Regex::new(r#"(?s)<ac:parameter ac:name="(\w+)">(.*?)</ac:parameter>"#)
+        .expect("regex should always compile");
+    let date_regex = Regex::new(r#"<time datetime="(\d{4}-\d{2}-\d{2})"\s*(?:/>|></time>)"#)
+        .expect("regex should always compile");
+    let markdown = status_regex.replace_all(markdown, |caps: &regex::Captures| {
+        let parameter = |name: &str| {
+            parameter_regex
+                .captures_iter(&caps[1])
+                .find(|parameter| &parameter[1] == name)
+                .map(|parameter| unescape_xml(&parameter[2]))
+        };
+        format!(
+            "{{status:{}|{}}}",
+            parameter("colour")
+                .unwrap_or_else(|| "grey".to_string())
+                .to_lowercase(),
+            parameter("title").unwrap_or_default()
+        )
+    });
+    date_regex.replace_all(&markdown, "{date:$1}").to_string()
+}
+
+// {status:colour|Title} and {date:2025-01-01} -> status macro and <time>,
+// outside of code
+fn shortcodes_to_widgets(markdown: &str) -> String {
+    let status_regex = Regex::new(r"\{status:(grey|red|yellow|green|blue|purple)\|([^}\n]*)\}")
+        .expect("regex should always compile");
+    let date_regex =
+        Regex::new(r"\{date:(\d{4}-\d{2}-\d{2})\}").expect("regex should always compile");
+    let markdown = replace_outside_code(&status_regex, markdown, |caps| {
+        // Grey is the default, and has no colour parameter
+        let colour = match &caps[1] {
+            "grey" => String::new(),
+            colour => format!(
+                "<ac:parameter ac:name=\"colour\">{}{}</ac:parameter>",
+                colour[..1].to_uppercase(),
+                &colour[1..]
+            ),
+        };
+        format!(
+            "<ac:structured-macro ac:name=\"status\" ac:schema-version=\"1\">{}<ac:parameter ac:name=\"title\">{}</ac:parameter></ac:structured-macro>",
+            colour,
+            escape_xml(&caps[2])
+        )
+    });
+    replace_outside_code(&date_regex, &markdown, |caps| {
+        format!("<time datetime=\"{}\" />", &caps[1])
+    })
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+// Table of contents macros -> [TOC] markers
+fn toc_macros_to_markers(markdown: &str) -> String {
+    let toc_regex = Regex::new(
+        r#"(?s)<ac:structured-macro ac:name="toc"[^>]*?(?:/>|>(.*?)</ac:structured-macro>)"#,
+    )
+    .expect("regex should always compile");
+    let parameter_regex = Regex::new(r#"(?s)<ac:parameter ac:name="(\w+)">(.*?)</ac:parameter>"#)
+        .expect("regex should always compile");
+    toc_regex
+        .replace_all(markdown, |caps: &regex::Captures| {
+            let body = caps.get(1).map_or("", |body| body.as_str());
+            let parameters = parameter_regex.captures_iter(body).map(|parameter| {
+                let (_, [name, value]) = parameter.extract();
+                (name, value)
+            });
+            match Toc::from_parameters(parameters) {
+                Some(toc) => toc.marker(),
+                None => caps[0].to_string(),
+            }
+        })
+        .to_string()
+}
+
+// [TOC] markers -> table of contents macros
+fn markers_to_toc_macros(markdown: &str) -> String {
+    let mut in_code_block = false;
+    markdown
+        .split_inclusive('\n')
+        .map(|line| {
+            if line.trim_start().starts_with("```") || line.trim_start().starts_with("~~~") {
+                in_code_block = !in_code_block;
+            }
+            (line, Toc::parse(line).filter(|_| !in_code_block))
+        })
+        .map(|(line, toc)| match toc {
+            Some(toc) => {
+                let parameters: String = toc
+                    .parameters()
+                    .iter()
+                    .map(|(name, value)| {
+                        format!("<ac:parameter ac:name=\"{}\">{}</ac:parameter>", name, value)
+                    })
+                    .collect();
+                format!(
+                    "<ac:structured-macro ac:name=\"toc\" ac:schema-version=\"1\">{}</ac:structured-macro>\n",
+                    parameters
+                )
+            }
+            None => line.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn word() -> impl Strategy<Value = String> {
+        "[a-z]{1,8}"
+    }
+
+    fn inline() -> impl Strategy<Value = String> {
+        prop_oneof![
+            word(),
+            word().prop_map(|word| format!("**{}**", word)),
+            word().prop_map(|word| format!("*{}*", word)),
+            word().prop_map(|word| format!("`{}`", word)),
+            word().prop_map(|word| format!("[{}](https://example.com/{})", word, word)),
+        ]
+    }
+
+    fn line() -> impl Strategy<Value = String> {
+        prop::collection::vec(inline(), 1..6).prop_map(|inlines| inlines.join(" "))
+    }
+
+    fn table() -> impl Strategy<Value = String> {
+        (1..4usize)
+            .prop_flat_map(|columns| {
+                prop::collection::vec(prop::collection::vec(word(), columns), 2..5)
+            })
+            .prop_map(|rows| {
+                let row = |cells: &Vec<String>| format!("| {} |", cells.join(" | "));
+                let divider = format!("|{}", " --- |".repeat(rows[0].len()));
+                let mut lines = vec![row(&rows[0]), divider];
+                lines.extend(rows[1..].iter().map(row));
+                lines.join("\n")
+            })
+    }
+
+    fn block() -> impl Strategy<Value = String> {
+        prop_oneof![
+            (1..4usize, line()).prop_map(|(level, line)| format!("{} {}", "#".repeat(level), line)),
+            line(),
+            prop::collection::vec(line(), 1..4).prop_map(|items| {
+                items
+                    .iter()
+                    .map(|item| format!("- {}", item))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }),
+            prop::collection::vec("[a-z]{1,6}( [a-z]{1,6}){0,3}", 1..4)
+                .prop_map(|lines| format!("```\n{}\n```", lines.join("\n"))),
+            table(),
+        ]
+    }
+
+    /// Markdown documents made of headings, paragraphs, lists, code blocks and
+    /// tables, with formatting, code and links inline
+    pub(crate) fn markdown_document() -> impl Strategy<Value = String> {
+        prop::collection::vec(block(), 1..8).prop_map(|blocks| blocks.join("\n\n") + "\n")
+    }
+
+    fn protected_element() -> impl Strategy<Value = String> {
+        prop_oneof![
+            word().prop_map(|title| format!(
+                "<ac:link><ri:page ri:content-title=\"{}\" /></ac:link>",
+                title
+            )),
+            word().prop_map(|kind| format!(
+                "<ac:structured-macro ac:name=\"chart\"><ac:parameter ac:name=\"type\">{}</ac:parameter></ac:structured-macro>",
+                kind
+            )),
+        ]
+    }
+
+    // Storage format paragraphs with protected elements between the words
+    fn storage_with_protected() -> impl Strategy<Value = (String, Vec<String>)> {
+        prop::collection::vec(
+            (prop::collection::vec(word(), 1..4), protected_element()),
+            1..6,
+        )
+        .prop_map(|paragraphs| {
+            let storage = paragraphs
+                .iter()
+                .map(|(words, element)| format!("<p>{} {}</p>", words.join(" "), element))
+                .collect();
+            let elements = paragraphs.into_iter().map(|(_, element)| element).collect();
+            (storage, elements)
+        })
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn storage_round_trip_keeps_the_text(markdown in markdown_document()) {
+            let round_tripped = to_markdown(&to_storage(&markdown, None), None);
+            prop_assert_eq!(text_blocks(&round_tripped), text_blocks(&markdown));
+        }
+
+        #[test]
+        fn placeholders_never_reach_the_markdown((storage, _) in storage_with_protected()) {
+            let markdown = to_markdown(&storage, None);
+            prop_assert!(!markdown.contains("CONCMDPROTECTED"), "{}", markdown);
+        }
+
+        #[test]
+        fn protected_elements_come_back_verbatim((storage, elements) in storage_with_protected()) {
+            let round_tripped = to_storage(&to_markdown(&storage, None), None);
+            prop_assert!(!round_tripped.contains("CONCMDPROTECTED"), "{}", round_tripped);
+            for element in &elements {
+                prop_assert!(
+                    round_tripped.contains(element.as_str()),
+                    "{} missing from {}",
+                    element,
+                    round_tripped
+                );
+            }
+        }
+    }
+}
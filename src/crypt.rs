@@ -0,0 +1,87 @@
+//! Encryption at rest for the files concmd keeps on disk, used when
+//! `encrypt_files` is set. Files are sealed with ChaCha20-Poly1305 under a key
+//! that is generated on first use and kept in the OS keyring, so they can only
+//! be read from the user's own login session.
+//!
+//! Encrypted files start with a short magic header. Anything without it is
+//! read as plain text, so files saved before the option was turned on, or
+//! written by hand, keep working.
+
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::path::Path;
+use std::sync::OnceLock;
+
+const MAGIC: &[u8] = b"concmd-encrypted-v1\n";
+const NONCE_LEN: usize = 12;
+const KEYRING_SERVICE: &str = "concmd";
+const KEYRING_USER: &str = "file-encryption-key";
+
+/// Whether data was written by [`encrypt`]
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Seals data with the keyring key and a fresh nonce
+pub fn encrypt(plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(key()?);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow!("Could not encrypt the file"))?;
+    Ok([MAGIC, nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Opens data sealed by [`encrypt`], returning anything else unchanged
+pub fn decrypt(data: &[u8]) -> Result<Vec<u8>> {
+    if !is_encrypted(data) {
+        return Ok(data.to_vec());
+    }
+    let sealed = &data[MAGIC.len()..];
+    if sealed.len() < NONCE_LEN {
+        return Err(anyhow!("The encrypted file is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(key()?)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Could not decrypt the file: it was changed or the key is wrong"))
+}
+
+/// Reads a file as text, decrypting it if it is encrypted
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String> {
+    let data = std::fs::read(&path)?;
+    String::from_utf8(decrypt(&data)?)
+        .with_context(|| format!("{} is not valid utf-8", path.as_ref().display()))
+}
+
+/// Writes text to a file, encrypted if asked to
+pub fn write<P: AsRef<Path>>(path: P, contents: &str, encrypted: bool) -> Result<()> {
+    match encrypted {
+        true => std::fs::write(path, encrypt(contents.as_bytes())?)?,
+        false => std::fs::write(path, contents)?,
+    }
+    Ok(())
+}
+
+// Read from the keyring once per run, creating it if this is the first time
+fn key() -> Result<&'static Key> {
+    static KEY: OnceLock<Key> = OnceLock::new();
+    if let Some(key) = KEY.get() {
+        return Ok(key);
+    }
+    let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)?;
+    let key = match entry.get_secret() {
+        Ok(secret) if secret.len() == 32 => *Key::from_slice(&secret),
+        Ok(_) => return Err(anyhow!("The encryption key in the keyring is corrupt")),
+        Err(keyring::Error::NoEntry) => {
+            let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+            entry
+                .set_secret(&key)
+                .context("Could not store the encryption key in the keyring")?;
+            key
+        }
+        Err(e) => return Err(e).context("Could not read the encryption key from the keyring"),
+    };
+    Ok(KEY.get_or_init(|| key))
+}
@@ -0,0 +1,75 @@
+//! Reading and writing the YAML frontmatter at the top of saved pages
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// YAML frontmatter written at the top of saved pages so the files describe
+/// which page they belong to. It is stripped off again before uploading.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct Frontmatter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub space: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<usize>,
+    /// The page's full set of labels. Unlike an empty list, leaving them out
+    /// leaves the labels alone on upload.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+/// The page a markdown file is published to, as named by its frontmatter
+#[derive(Debug, PartialEq)]
+pub enum PageRef<'a> {
+    /// No page yet, so one is created, in the frontmatter's space if it names
+    /// one
+    New { space: Option<&'a str> },
+    /// The page the file was downloaded from, and the version it was
+    /// downloaded at if the frontmatter records it
+    Existing { id: &'a str, version: Option<usize> },
+}
+
+impl Frontmatter {
+    pub fn page_ref(&self) -> PageRef<'_> {
+        match &self.id {
+            Some(id) => PageRef::Existing {
+                id,
+                version: self.version,
+            },
+            None => PageRef::New {
+                space: self.space.as_deref(),
+            },
+        }
+    }
+}
+
+const DELIMITER: &str = "---";
+
+/// Splits a markdown file into its frontmatter, if it has any, and the body
+pub fn split(text: &str) -> Result<(Option<Frontmatter>, &str)> {
+    let Some(rest) = text.strip_prefix(&format!("{}\n", DELIMITER)) else {
+        return Ok((None, text));
+    };
+    let Some(end) = rest.find(&format!("\n{}\n", DELIMITER)) else {
+        return Ok((None, text));
+    };
+    let frontmatter = serde_yaml::from_str::<Frontmatter>(&rest[..end])
+        .context("The frontmatter could not be parsed: check the formatting")?;
+    let body = &rest[end + DELIMITER.len() + 2..];
+    Ok((Some(frontmatter), body.strip_prefix('\n').unwrap_or(body)))
+}
+
+pub fn join(frontmatter: &Frontmatter, body: &str) -> Result<String> {
+    Ok(format!(
+        "{}\n{}{}\n\n{}",
+        DELIMITER,
+        serde_yaml::to_string(frontmatter)?,
+        DELIMITER,
+        body
+    ))
+}
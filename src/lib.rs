@@ -0,0 +1,29 @@
+//! Confluence Cloud access and page conversion, as used by the concmd command
+//! line tool.
+//!
+//! [`conf_api`] wraps the Confluence REST api (pages, spaces, labels, tasks...),
+//! [`convert`] converts page bodies between storage format and markdown, with
+//! [`adf`] doing the same for pages stored as ADF, and [`frontmatter`] reads
+//! and writes the metadata concmd puts at the top of saved pages. [`lint`]
+//! finds markdown that won't convert cleanly before it is uploaded, and
+//! [`crypt`] encrypts the pages concmd saves to disk.
+//!
+//! ```no_run
+//! use concmd::conf_api::Page;
+//! use concmd::{convert, Config};
+//!
+//! let config = Config::read_config(&"config.toml")?;
+//! let page = Page::get_page_by_id(&config.api, &"12345".to_string())?;
+//! let markdown = convert::body_to_markdown(page.get_body(), page.body_format(), Some(&config))?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod adf;
+pub mod conf_api;
+mod config;
+pub mod convert;
+pub mod crypt;
+pub mod frontmatter;
+pub mod lint;
+
+pub use config::{Api, AutoSync, Config, Confirm, Jira, LegacyEditor, SaveMode};
@@ -0,0 +1,84 @@
+//! Checks markdown for constructs that don't survive conversion to a page body,
+//! so they can be reported before uploading rather than found on the published
+//! page.
+//!
+//! Raw xml in the ac: and ri: namespaces is fine: that is how
+//! [`convert`](crate::convert) keeps macros it can't convert, and it is put
+//! back verbatim on upload. So are `<details>` blocks, which become expand
+//! macros.
+
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+use std::fmt;
+
+/// A construct that is known to convert badly, with the line it starts on
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Returns a warning for each raw html block or tag, footnote, definition list
+/// and table nested in a list or quote. Line numbers count from first_line, so
+/// the caller can account for frontmatter split off the top of the file.
+pub fn lint(markdown: &str, first_line: usize) -> Vec<Warning> {
+    // The converters don't enable footnotes or definition lists, which is
+    // exactly why they are worth finding: without these options they would
+    // silently turn into plain paragraphs
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_DEFINITION_LIST);
+
+    let line_of = |offset: usize| first_line + markdown[..offset].matches('\n').count();
+    let mut warnings = Vec::new();
+    let mut warn = |offset: usize, message: &str| {
+        warnings.push(Warning {
+            line: line_of(offset),
+            message: message.to_string(),
+        })
+    };
+    // Lists and quotes the parser is currently inside
+    let mut containers = 0;
+    for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::HtmlBlock) if !is_supported_html(&markdown[range.clone()]) => {
+                warn(range.start, "raw html block")
+            }
+            Event::InlineHtml(html) if !is_supported_html(&html) => {
+                warn(range.start, &format!("raw html tag {}", html))
+            }
+            Event::FootnoteReference(name) => {
+                warn(range.start, &format!("footnote reference [^{}]", name))
+            }
+            Event::Start(Tag::FootnoteDefinition(name)) => {
+                warn(range.start, &format!("footnote definition [^{}]", name))
+            }
+            Event::Start(Tag::DefinitionList) => warn(range.start, "definition list"),
+            Event::Start(Tag::Table(_)) if containers > 0 => {
+                warn(range.start, "table inside a list or quote")
+            }
+            Event::Start(Tag::List(_) | Tag::BlockQuote(_)) => containers += 1,
+            Event::End(TagEnd::List(_) | TagEnd::BlockQuote(_)) => containers -= 1,
+            _ => (),
+        }
+    }
+    warnings
+}
+
+fn is_supported_html(html: &str) -> bool {
+    let tag = html
+        .trim_start()
+        .trim_start_matches("</")
+        .trim_start_matches('<');
+    ["ac:", "ri:", "time ", "details>", "summary>"]
+        .iter()
+        .any(|start| tag.starts_with(start))
+}
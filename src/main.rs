@@ -1,16 +1,20 @@
 mod actions;
-mod conf_api;
+mod audit;
+mod checkpoint;
+mod mentions;
+mod plan;
+mod serve;
+mod state;
 
 use anyhow::{Context, Result};
-use serde::{de::Error, Deserialize, Deserializer};
-use std::fs::File;
-use std::{
-    io::Read,
-    path::{Path, PathBuf},
-};
-use toml;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Parser;
+use concmd::conf_api::{Restriction, WatchTarget};
+use concmd::{Config, LegacyEditor};
+
+use crate::actions::CommandOutcome;
 
 // Command line interface for clap
 #[derive(Parser, Debug)]
@@ -18,6 +22,15 @@ use clap::Parser;
 struct Args {
     #[command(subcommand)]
     action: Action,
+
+    // Seconds to wait for each request to Confluence, overriding
+    // request_timeout in the config
+    #[arg(long, global = true)]
+    timeout: Option<u64>,
+
+    // Only print errors and the data a command was asked for, e.g. for scripts
+    #[arg(short, long, global = true)]
+    quiet: bool,
 }
 
 #[derive(Debug, clap::Subcommand)]
@@ -42,55 +55,566 @@ enum Action {
         #[arg(short, long)]
         filename: PathBuf,
     },
+    #[command(args_conflicts_with_subcommands = true)]
     Edit {
         #[arg(short, long)]
+        id: Option<String>,
+
+        // A page's path of titles from its space, e.g. ENG/Guides/Onboarding.
+        // The space's homepage can be left out.
+        #[arg(short, long, conflicts_with = "id")]
+        path: Option<String>,
+
+        // Edit pages made in the legacy editor even with legacy_editor =
+        // 'refuse' in the config
+        #[arg(long)]
+        force: bool,
+
+        #[command(subcommand)]
+        target: Option<EditTarget>,
+    },
+    New {
+        // Can be left out when there is a title template
+        #[arg(short, long)]
+        title: Option<String>,
+
+        // Overrides title_template in the config, e.g. "{date} standup"
+        #[arg(long)]
+        title_template: Option<String>,
+
+        #[arg(short, long)]
+        space: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = OnConflict::Error)]
+        on_conflict: OnConflict,
+    },
+    Upload {
+        // A markdown file, a page id to upload its saved file, - for stdin, or
+        // a quoted glob like "notes/*.md" to upload several files
+        #[arg(short, long, visible_alias = "file")]
+        path: PathBuf,
+
+        #[arg(short, long)]
+        title: Option<String>,
+
+        // Overrides title_template in the config, e.g. "{filename} — {date}"
+        #[arg(long)]
+        title_template: Option<String>,
+
+        #[arg(short, long)]
+        space: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = OnConflict::Error)]
+        on_conflict: OnConflict,
+
+        // Refuse to upload if the markdown has constructs that convert badly
+        #[arg(long)]
+        strict: bool,
+
+        // Download the page again afterwards and report any content lost
+        #[arg(long)]
+        verify: bool,
+    },
+    Verify {
+        id: String,
+
+        // The markdown the page was published from, by default its saved file
+        #[arg(short, long)]
+        path: Option<PathBuf>,
+    },
+    Apply {
+        #[arg(short, long)]
+        space: String,
+
+        // A list of operations as .json, .yaml/.yml or .csv, see plan.rs
+        #[arg(short, long)]
+        plan: PathBuf,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Read {
+        id: String,
+
+        // Print the start of the page instead of opening the pager, e.g. 50l
+        // for lines, 2000c for characters or full
+        #[arg(long, num_args = 0..=1, default_missing_value = "20l", value_parser = parse_preview_length)]
+        preview: Option<PreviewLength>,
+    },
+    Info {
+        id: String,
+
+        #[arg(long, value_enum)]
+        copy: Option<CopyTarget>,
+    },
+    // Lists spaces, optionally only those whose name or key contains filter
+    Spaces {
+        filter: Option<String>,
+
+        // Remembered for later runs, and for the list new and upload choose a
+        // space from
+        #[arg(long)]
+        sort: Option<SpaceSort>,
+
+        // Only list spaces with this label
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+    View {
+        #[arg(short, long)]
+        space: String,
+
+        #[arg(long)]
+        plain: bool,
+
+        #[arg(long)]
+        include_archived: bool,
+    },
+    Tree {
+        #[arg(short, long)]
+        space: String,
+    },
+    Tasks {
+        #[arg(short, long)]
+        space: String,
+
+        #[arg(short, long)]
+        mine: bool,
+    },
+    Convert {
+        #[arg(short, long)]
+        from: Format,
+
+        #[arg(short, long)]
+        to: Format,
+    },
+    Restrict {
         id: String,
+
+        #[arg(long)]
+        view: Vec<Restriction>,
+
+        #[arg(long)]
+        edit: Vec<Restriction>,
+    },
+    Watch {
+        #[command(subcommand)]
+        action: WatchAction,
+    },
+    Clean {
+        #[arg(long, value_parser = parse_age)]
+        older_than: Option<Duration>,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Auth {
+        #[command(subcommand)]
+        action: AuthAction,
+    },
+    Pick {
+        #[arg(short, long)]
+        space: Option<String>,
+
+        #[arg(short, long)]
+        edit: bool,
+
+        #[arg(long)]
+        include_archived: bool,
+    },
+    // Searches page titles across every space the user can see
+    Search {
+        query: String,
+
+        #[arg(short, long)]
+        edit: bool,
+    },
+    // Lists the pages with exactly this title, where search matches words
+    Find {
+        #[arg(short, long)]
+        title: String,
+
+        #[arg(short, long)]
+        space: Option<String>,
+
+        // Choose one of the pages to edit, or edit it straight away if there
+        // is only one
+        #[arg(long)]
+        pick: bool,
+    },
+    // Runs a raw CQL search, for anything the other commands don't cover
+    Cql {
+        query: String,
+
+        #[arg(long)]
+        json: bool,
+    },
+    Replace {
+        #[arg(short, long)]
+        space: String,
+
+        #[arg(short, long)]
+        find: String,
+
+        #[arg(short, long)]
+        replace: String,
+
+        // Treat find as a regular expression, replace can then use $1 etc.
+        #[arg(long)]
+        regex: bool,
+
+        #[arg(long)]
+        dry_run: bool,
+
+        // Ignore the checkpoint left by an earlier run and go through every page
+        #[arg(long)]
+        restart: bool,
+    },
+    Stats {
+        #[arg(short, long)]
+        space: String,
+
+        // Pages not updated for this long are counted as untouched
+        #[arg(long, value_parser = parse_age, default_value = "180d")]
+        untouched_for: Duration,
+
+        #[arg(long)]
+        json: bool,
+    },
+    Stale {
+        #[arg(short, long)]
+        space: String,
+
+        #[arg(long, value_parser = parse_age, default_value = "180d")]
+        than: Duration,
+
+        // Only pages with this label
+        #[arg(short, long)]
+        label: Option<String>,
+
+        // Label to add to each stale page, e.g. outdated
+        #[arg(long)]
+        add_label: Option<String>,
+
+        // Markdown comment to post on each stale page
+        #[arg(long)]
+        comment: Option<String>,
+    },
+    Archive {
+        id: String,
+    },
+    Unarchive {
+        id: String,
+    },
+    // Moves a page to the trash, or deletes it for good with --purge
+    Delete {
+        id: String,
+
+        #[arg(long)]
+        purge: bool,
+    },
+    Prop {
+        #[command(subcommand)]
+        action: PropAction,
+    },
+    // Publishes the markdown files in a git repo that changed since the last
+    // sync, each to the page whose id is in its frontmatter
+    GitSync {
+        #[arg(short, long, default_value = ".")]
+        repo: PathBuf,
+
+        #[arg(long)]
+        dry_run: bool,
+
+        // Ignore the checkpoint left by an earlier run and publish every
+        // changed file
+        #[arg(long)]
+        restart: bool,
+    },
+    // Saves every page in a space as markdown files in a directory
+    Export {
+        #[arg(short, long)]
+        space: String,
+
+        #[arg(short, long)]
+        dir: PathBuf,
+
+        // Pages to fetch and convert at once, overriding export_jobs in the
+        // config
+        #[arg(short, long)]
+        jobs: Option<usize>,
+
+        // Ignore the checkpoint left by an earlier run and export every page
+        #[arg(long)]
+        restart: bool,
+    },
+    // Polls a space for page changes, printing each as a line of json or
+    // running a command for it, e.g. to post to a chat channel
+    WatchRemote {
+        #[arg(short, long)]
+        space: String,
+
+        // Seconds between polls
+        #[arg(short, long, default_value_t = 60)]
+        interval: u64,
+
+        // Shell command to run for each change instead of printing it, with
+        // the change in CONCMD_EVENT, CONCMD_SPACE, CONCMD_PAGE_ID,
+        // CONCMD_PAGE_TITLE, CONCMD_PAGE_VERSION and CONCMD_PAGE_URL
+        #[arg(short, long)]
+        exec: Option<String>,
+    },
+    // Reverts the last page update concmd made that hasn't been undone, by
+    // publishing the version before it again
+    Undo,
+    // Shows the changes concmd has made on Confluence, oldest first: pages
+    // created, updated, moved and deleted, and labels, restrictions, watches,
+    // properties, comments and tasks
+    Log {
+        // Only changes to this page
+        #[arg(short, long)]
+        page: Option<String>,
+
+        #[arg(short, long)]
+        operation: Option<audit::Operation>,
+
+        // Only the most recent n matching changes
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        #[arg(long)]
+        json: bool,
+    },
+    // Started by info --copy to keep the copied text on the clipboard
+    #[command(hide = true)]
+    HoldClipboard,
+    // JSON-RPC server for editor plugins, see serve.rs
+    Serve {
+        #[arg(long)]
+        stdio: bool,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum EditTarget {
+    // Opens all the pages in one editor session
+    Ids {
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+    // Opens the space's homepage
+    Home {
+        space: String,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum WatchAction {
+    Add(WatchArgs),
+    Remove(WatchArgs),
+    List,
+}
+
+#[derive(Debug, clap::Args)]
+#[group(required = true, multiple = false)]
+struct WatchArgs {
+    #[arg(short, long)]
+    page: Option<String>,
+
+    #[arg(short, long)]
+    space: Option<String>,
+}
+
+impl WatchArgs {
+    fn target(&self) -> WatchTarget {
+        match (&self.page, &self.space) {
+            (Some(id), _) => WatchTarget::Page(id.clone()),
+            (None, Some(key)) => WatchTarget::Space(key.clone()),
+            (None, None) => unreachable!("clap requires a page or space"),
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum PropAction {
+    Get {
+        #[arg(short, long)]
+        page: String,
+
+        key: String,
+    },
+    // The value is stored as json if it parses as json, otherwise as a string
+    Set {
+        #[arg(short, long)]
+        page: String,
+
+        key: String,
+
+        value: String,
+    },
+    List {
+        #[arg(short, long)]
+        page: String,
     },
 }
 
-// Config structure. Note deserialize_with for save_location, see fn
-#[derive(Deserialize, Debug)]
-struct Config {
-    #[serde(deserialize_with = "from_tilde_path")]
-    save_location: PathBuf,
-    api: Api,
+#[derive(Debug, clap::Subcommand)]
+enum AuthAction {
+    Check,
+}
+
+// What to do when creating a page whose title is already taken in the space
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum)]
+enum OnConflict {
+    Error,
+    Suffix,
+    Update,
+}
+
+// What info --copy puts on the clipboard
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum)]
+enum CopyTarget {
+    Id,
+    Url,
+    Body,
+}
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+enum SpaceSort {
+    #[default]
+    Name,
+    Key,
+    // Spaces used most recently with new and upload first
+    Recent,
+}
+
+#[derive(Debug, Clone, PartialEq, clap::ValueEnum)]
+enum Format {
+    Md,
+    Storage,
+    Adf,
+}
+
+// Parses ages like "30d" or "12h" for command line arguments. Supports
+// seconds, minutes, hours, days and weeks.
+fn parse_age(age: &str) -> Result<Duration> {
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (number, unit) = age.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid age \"{}\", expected e.g. 30d", age))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" | "" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => anyhow::bail!("Unknown unit \"{}\" in age, expected s, m, h, d or w", unit),
+    };
+    let seconds = number
+        .checked_mul(seconds)
+        .with_context(|| format!("Age \"{}\" is too long", age))?;
+    Ok(Duration::from_secs(seconds))
+}
+
+// How much of a page to preview
+#[derive(Debug, Clone, PartialEq)]
+enum PreviewLength {
+    Lines(usize),
+    Chars(usize),
+    Full,
+}
+
+// Parses preview lengths like "50l" or "2000c" for command line arguments. A
+// bare number counts lines.
+fn parse_preview_length(length: &str) -> Result<PreviewLength> {
+    if length == "full" {
+        return Ok(PreviewLength::Full);
+    }
+    let split = length
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(length.len());
+    let (number, unit) = length.split_at(split);
+    let number: usize = number.parse().with_context(|| {
+        format!("Invalid preview length \"{}\", expected e.g. 50l, 2000c or full", length)
+    })?;
+    if number == 0 {
+        anyhow::bail!("A preview length of 0 shows nothing, use at least 1 or full");
+    }
+    match unit {
+        "l" | "" => Ok(PreviewLength::Lines(number)),
+        "c" => Ok(PreviewLength::Chars(number)),
+        _ => anyhow::bail!("Unknown unit \"{}\" in preview length, expected l or c", unit),
+    }
+}
+
+fn read_config(path: &Path, timeout: Option<u64>) -> Result<Config> {
+    let mut config = Config::read_config(&path)?;
+    if let Some(timeout) = timeout {
+        config.api.request_timeout = timeout;
+    }
+    Ok(config)
 }
 
-impl Config {
-    fn read_config<P: AsRef<Path>>(file_name: &P) -> Result<Config> {
-        let mut contents = String::new();
-        let mut file = File::open(&file_name).context("Config file could not be found")?;
-        file.read_to_string(&mut contents)
-            .context("File is not readable")?;
-        toml::from_str::<Config>(contents.as_str())
-            .context("The config file could not be parsed: check the formatting")
+// Reads the config and, before running any command against Confluence, deals
+// with edits left unsynced by a previous run
+fn load_config(path: &Path, timeout: Option<u64>) -> Config {
+    let config = read_config(path, timeout).unwrap();
+    crate::actions::recover_unsynced_edits(&config);
+    config
+}
+
+fn with_force(mut config: Config, force: bool) -> Config {
+    if force {
+        config.legacy_editor = LegacyEditor::Warn;
     }
+    config
 }
 
-#[derive(Deserialize, Debug)]
-struct Api {
-    confluence_domain: String,
-    username: String,
-    token: String,
+// Picks the edit command for the flags given. Adding a way to choose pages to
+// edit is a new arm here returning a CommandOutcome.
+fn dispatch_edit(
+    config: &Config,
+    id: Option<&str>,
+    path: Option<&str>,
+    target: Option<&EditTarget>,
+) -> Result<CommandOutcome> {
+    match (id, path, target) {
+        (Some(id), _, _) => crate::actions::edit_page_by_id(config, id),
+        (None, Some(path), _) => crate::actions::edit_page_by_path(config, path),
+        (None, None, Some(EditTarget::Ids { ids })) => {
+            crate::actions::edit_pages_by_id(config, ids)
+        }
+        (None, None, Some(EditTarget::Home { space })) => {
+            crate::actions::edit_space_homepage(config, space)
+        }
+        (None, None, None) => Ok(CommandOutcome::Failed(
+            "Give a page with --id or --path, or several with edit ids".to_string(),
+        )),
+    }
 }
 
-// Implements a custom deserializer for save_location that automatically
-// expands the tilde to the users home directory (unix only)
-fn from_tilde_path<'de, D>(deserializer: D) -> Result<PathBuf, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s: String = Deserialize::deserialize(deserializer)?;
-    expanduser::expanduser(s).map_err(D::Error::custom)
+fn with_title_template(mut config: Config, title_template: &Option<String>) -> Config {
+    if let Some(title_template) = title_template {
+        config.title_template = Some(title_template.clone());
+    }
+    config
 }
 
 fn main() {
     let mut home_dir = home::home_dir().expect("home dir should always exist");
     home_dir.push(".config/concmd/config.toml");
 
-    let config = Config::read_config(&home_dir).unwrap();
-
     let cli = Args::parse();
+    crate::actions::handle_interrupts();
+    crate::actions::set_quiet(cli.quiet);
+
+    // Only read when a command needs it, so local commands like convert work
+    // without a config file
+    let config = || load_config(&home_dir, cli.timeout);
 
     match &cli.action {
         Action::Fetch {
@@ -103,6 +627,251 @@ fn main() {
             page,
             filename,
         } => crate::actions::publish_page(space, page, filename),
-        Action::Edit { id } => crate::actions::edit_page_by_id(&config, id),
+        Action::Edit {
+            id,
+            path,
+            force,
+            target,
+        } => crate::actions::exit_with(dispatch_edit(
+            &with_force(config(), *force),
+            id.as_deref(),
+            path.as_deref(),
+            target.as_ref(),
+        )),
+        Action::New {
+            title,
+            title_template,
+            space,
+            on_conflict,
+        } => crate::actions::new_page(
+            &with_title_template(config(), title_template),
+            title.as_deref(),
+            space.as_deref(),
+            on_conflict,
+        ),
+        Action::Upload {
+            path,
+            title,
+            title_template,
+            space,
+            on_conflict,
+            strict,
+            verify,
+        } => crate::actions::upload_file(
+            &with_title_template(config(), title_template),
+            path,
+            title.as_deref(),
+            space.as_deref(),
+            on_conflict,
+            *strict,
+            *verify,
+        ),
+        Action::Verify { id, path } => crate::actions::verify_page(&config(), id, path.as_deref()),
+        Action::Apply {
+            space,
+            plan,
+            dry_run,
+        } => crate::actions::apply_plan(&config(), space, plan, *dry_run),
+        Action::Read { id, preview } => {
+            crate::actions::read_page_by_id(&config(), id, preview.as_ref())
+        }
+        Action::Info { id, copy } => crate::actions::page_info(&config(), id, copy.as_ref()),
+        Action::Spaces {
+            filter,
+            sort,
+            label,
+        } => crate::actions::list_spaces(&config(), filter.as_deref(), *sort, label.as_deref()),
+        Action::View {
+            space,
+            plain,
+            include_archived,
+        } => crate::actions::view_space(&config(), space, *plain, *include_archived),
+        Action::Tree { space } => crate::actions::print_space_tree(&config(), space),
+        Action::Tasks { space, mine } => crate::actions::list_open_tasks(&config(), space, *mine),
+        // Uses the config if there is one, but conversion works without it
+        Action::Convert { from, to } => crate::actions::convert_stdin(
+            read_config(&home_dir, cli.timeout).ok().as_ref(),
+            from,
+            to,
+        ),
+        Action::Restrict { id, view, edit } => {
+            crate::actions::restrict_page(&config(), id, view, edit)
+        }
+        Action::Watch { action } => match action {
+            WatchAction::Add(args) => crate::actions::watch(&config(), &args.target()),
+            WatchAction::Remove(args) => crate::actions::unwatch(&config(), &args.target()),
+            WatchAction::List => crate::actions::list_watched(&config()),
+        },
+        Action::Clean {
+            older_than,
+            dry_run,
+        } => crate::actions::clean_save_location(&config(), *older_than, *dry_run),
+        // Skips unsynced edit recovery so it never stops to prompt in CI
+        Action::Auth {
+            action: AuthAction::Check,
+        } => {
+            let config = crate::actions::config_or_exit(read_config(&home_dir, cli.timeout));
+            crate::actions::check_auth(&config)
+        }
+        Action::Pick {
+            space,
+            edit,
+            include_archived,
+        } => crate::actions::pick_page(&config(), space.as_deref(), *edit, *include_archived),
+        Action::Search { query, edit } => crate::actions::search_pages(&config(), query, *edit),
+        Action::Find { title, space, pick } => crate::actions::exit_with(
+            crate::actions::find_pages(&config(), title, space.as_deref(), *pick),
+        ),
+        Action::Cql { query, json } => crate::actions::run_cql(&config(), query, *json),
+        Action::Replace {
+            space,
+            find,
+            replace,
+            regex,
+            dry_run,
+            restart,
+        } => crate::actions::replace_in_space(
+            &config(),
+            space,
+            find,
+            replace,
+            *regex,
+            *dry_run,
+            *restart,
+        ),
+        Action::Stats {
+            space,
+            untouched_for,
+            json,
+        } => crate::actions::space_stats(&config(), space, *untouched_for, *json),
+        Action::Stale {
+            space,
+            than,
+            label,
+            add_label,
+            comment,
+        } => crate::actions::stale_pages(
+            &config(),
+            space,
+            *than,
+            label.as_deref(),
+            add_label.as_deref(),
+            comment.as_deref(),
+        ),
+        Action::Archive { id } => crate::actions::archive_page(&config(), id),
+        Action::Unarchive { id } => crate::actions::unarchive_page(&config(), id),
+        Action::Delete { id, purge } => crate::actions::delete_page(&config(), id, *purge),
+        Action::Prop { action } => match action {
+            PropAction::Get { page, key } => crate::actions::get_property(&config(), page, key),
+            PropAction::Set { page, key, value } => {
+                crate::actions::set_property(&config(), page, key, value)
+            }
+            PropAction::List { page } => crate::actions::list_properties(&config(), page),
+        },
+        Action::GitSync {
+            repo,
+            dry_run,
+            restart,
+        } => crate::actions::git_sync(&config(), repo, *dry_run, *restart),
+        Action::Export {
+            space,
+            dir,
+            jobs,
+            restart,
+        } => crate::actions::export_space(&config(), space, dir, *jobs, *restart),
+        Action::WatchRemote {
+            space,
+            interval,
+            exec,
+        } => crate::actions::watch_remote(
+            &config(),
+            space,
+            Duration::from_secs(*interval),
+            exec.as_deref(),
+        ),
+        Action::Undo => crate::actions::undo_last_update(&config()),
+        // Read from the local log, so no config is needed
+        Action::Log {
+            page,
+            operation,
+            limit,
+            json,
+        } => crate::actions::show_log(page.as_deref(), operation.as_ref(), *limit, *json),
+        Action::HoldClipboard => crate::actions::hold_clipboard(),
+        // stdin belongs to the client, so there is no unsynced edit prompt
+        Action::Serve { stdio } => {
+            let config = crate::actions::config_or_exit(read_config(&home_dir, cli.timeout));
+            crate::actions::serve(&config, *stdio)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        toml::from_str(
+            "save_location = '/tmp/concmd'\n\
+             [api]\n\
+             confluence_domain = 'example.atlassian.net'\n\
+             username = 'user@example.com'\n\
+             token = 'token'\n",
+        )
+        .expect("the test config should parse")
+    }
+
+    #[test]
+    fn edit_without_a_page_fails() {
+        let outcome = dispatch_edit(&test_config(), None, None, None).unwrap();
+        assert!(matches!(outcome, CommandOutcome::Failed(_)));
+    }
+
+    #[test]
+    fn ages_default_to_days() {
+        assert_eq!(
+            parse_age("30").unwrap(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert_eq!(parse_age("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert_eq!(
+            parse_age("2w").unwrap(),
+            Duration::from_secs(14 * 24 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn bad_ages_are_rejected() {
+        assert!(parse_age("30x").is_err());
+        assert!(parse_age("").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("-5d").is_err());
+        assert!(parse_age("99999999999999999w").is_err());
+    }
+
+    #[test]
+    fn preview_lengths_default_to_lines() {
+        assert_eq!(
+            parse_preview_length("50").unwrap(),
+            PreviewLength::Lines(50)
+        );
+        assert_eq!(
+            parse_preview_length("50l").unwrap(),
+            PreviewLength::Lines(50)
+        );
+        assert_eq!(
+            parse_preview_length("2000c").unwrap(),
+            PreviewLength::Chars(2000)
+        );
+        assert_eq!(parse_preview_length("full").unwrap(), PreviewLength::Full);
+    }
+
+    #[test]
+    fn bad_preview_lengths_are_rejected() {
+        assert!(parse_preview_length("0l").is_err());
+        assert!(parse_preview_length("0").is_err());
+        assert!(parse_preview_length("20w").is_err());
+        assert!(parse_preview_length("l").is_err());
+        assert!(parse_preview_length("Full").is_err());
     }
 }
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use pulldown_cmark::{Event, Parser, Tag};
+use regex::{Captures, Regex};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::ops::Range;
+
+use concmd::conf_api::{BodyFormat, User};
+use concmd::Api;
+
+// User mentions are written as @[Display Name] in markdown, or @first.last as
+// a shorthand when typing them. On upload each name is looked up with the user
+// search, asking the user to pick when several people match, and swapped for
+// the mention element of the page's body format. On download mentions become
+// @[Display Name] again.
+
+// Replaces storage format and ADF mentions with @[Display Name]
+pub fn mentions_to_names(api: &Api, markdown: &str) -> Result<String> {
+    let storage_mention = Regex::new(
+        r#"<ac:link[^>]*>\s*<ri:user [^>]*ri:account-id="([^"]+)"[^>]*/>\s*</ac:link>"#,
+    )?;
+    let adf_mention = Regex::new(r"`adf:(\{[^`]*\})`")?;
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut name_of = |account_id: &str| -> Result<String> {
+        if let Some(name) = names.get(account_id) {
+            return Ok(name.clone());
+        }
+        let name = User::get_user(api, account_id)?.display_name;
+        names.insert(account_id.to_string(), name.clone());
+        Ok(name)
+    };
+
+    let markdown = replace_all(&storage_mention, markdown, |caps| {
+        Ok(Some(format!("@[{}]", name_of(&caps[1])?)))
+    })?;
+    replace_all(&adf_mention, &markdown, |caps| {
+        let node: Value = serde_json::from_str(&caps[1])?;
+        match (node["type"].as_str(), node["attrs"]["id"].as_str()) {
+            (Some("mention"), Some(account_id)) => Ok(Some(format!("@[{}]", name_of(account_id)?))),
+            _ => Ok(None),
+        }
+    })
+}
+
+// Replaces @[Display Name] and @first.last outside of code with mentions in
+// the given body format. Names that match nobody are left as they are.
+pub fn names_to_mentions(api: &Api, markdown: &str, format: BodyFormat) -> Result<String> {
+    // A link straight after the name means @[text](url), which is not a mention
+    let bracketed = Regex::new(r"@\[([^\]\n]+)\](\()?")?;
+    // Not after a word character, so email addresses are left alone
+    let dotted = Regex::new(r"(^|[^\w@.\[/])@([A-Za-z][\w-]*(?:\.[\w-]+)+)")?;
+    let mut accounts: HashMap<String, Option<User>> = HashMap::new();
+    let mut mention = |name: &str| -> Result<Option<String>> {
+        if !accounts.contains_key(name) {
+            accounts.insert(name.to_string(), choose_user(api, name)?);
+        }
+        Ok(accounts[name]
+            .as_ref()
+            .map(|user| mention_element(user, format)))
+    };
+
+    let code = code_ranges(markdown);
+    let markdown = replace_all(&bracketed, markdown, |caps| {
+        let whole = caps.get(0).expect("group 0 is the whole match");
+        if caps.get(2).is_some() || in_ranges(&code, whole.start()) {
+            return Ok(None);
+        }
+        mention(&caps[1])
+    })?;
+    let code = code_ranges(&markdown);
+    replace_all(&dotted, &markdown, |caps| {
+        let name = caps.get(2).expect("the pattern has two groups");
+        if in_ranges(&code, name.start()) {
+            return Ok(None);
+        }
+        let element = mention(&name.as_str().replace('.', " "))?;
+        Ok(element.map(|element| format!("{}{}", &caps[1], element)))
+    })
+}
+
+// Prefers a single exact match on the display name, otherwise asks
+fn choose_user(api: &Api, name: &str) -> Result<Option<User>> {
+    let mut users: Vec<User> = User::search(api, name)?
+        .into_iter()
+        .filter(|user| user.user_type == "known")
+        .collect();
+    let exact: Vec<usize> = (0..users.len())
+        .filter(|&i| users[i].display_name.eq_ignore_ascii_case(name))
+        .collect();
+    if let [i] = exact[..] {
+        return Ok(Some(users.swap_remove(i)));
+    }
+    match users.len() {
+        0 => {
+            eprintln!("Warning: no user matches @{}, leaving it as text", name);
+            Ok(None)
+        }
+        1 => Ok(users.pop()),
+        _ => {
+            println!("Several users match @{}:", name);
+            for (i, user) in users.iter().enumerate() {
+                let email = user.email.as_deref().unwrap_or("no email");
+                println!("  {}. {} ({})", i + 1, user.display_name, email);
+            }
+            loop {
+                print!("Select a user number:  ");
+                let selection: String = text_io::read!("{}\n");
+                match selection.trim().parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= users.len() => {
+                        return Ok(Some(users.swap_remove(n - 1)))
+                    }
+                    _ => continue,
+                }
+            }
+        }
+    }
+}
+
+// Both forms are left for the converters to pass through untouched: storage
+// xml is protected, and ADF nodes in an adf: code span are parsed as they are
+fn mention_element(user: &User, format: BodyFormat) -> String {
+    match format {
+        BodyFormat::Storage => format!(
+            r#"<ac:link><ri:user ri:account-id="{}" /></ac:link>"#,
+            user.account_id
+        ),
+        BodyFormat::Adf => {
+            let node = json!({
+                "type": "mention",
+                "attrs": {"id": user.account_id, "text": format!("@{}", user.display_name)},
+            });
+            format!("`adf:{}`", node)
+        }
+    }
+}
+
+// Like Regex::replace_all, but the replacement can fail or decline to replace
+// a match by returning None
+fn replace_all(
+    regex: &Regex,
+    text: &str,
+    mut replace: impl FnMut(&Captures) -> Result<Option<String>>,
+) -> Result<String> {
+    let mut result = String::new();
+    let mut last = 0;
+    for caps in regex.captures_iter(text) {
+        let whole = caps.get(0).expect("group 0 is the whole match");
+        if let Some(replacement) = replace(&caps)? {
+            result.push_str(&text[last..whole.start()]);
+            result.push_str(&replacement);
+            last = whole.end();
+        }
+    }
+    result.push_str(&text[last..]);
+    Ok(result)
+}
+
+// Byte ranges of code spans and blocks, where an @ is never a mention
+fn code_ranges(markdown: &str) -> Vec<Range<usize>> {
+    Parser::new(markdown)
+        .into_offset_iter()
+        .filter(|(event, _)| matches!(event, Event::Code(_) | Event::Start(Tag::CodeBlock(_))))
+        .map(|(_, range)| range)
+        .collect()
+}
+
+fn in_ranges(ranges: &[Range<usize>], offset: usize) -> bool {
+    ranges.iter().any(|range| range.contains(&offset))
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// A plan file is a list of operations that are run in order against a single
+// space, written as YAML if the file ends in .yaml or .yml, CSV if it ends in
+// .csv and JSON otherwise, e.g.
+//
+// [
+//     { "op": "create", "title": "Runbooks", "parent_id": "12345" },
+//     { "op": "rename", "id": "23456", "title": "Old runbooks" },
+//     { "op": "add_label", "id": "23456", "label": "archive" },
+//     { "op": "move", "id": "23456", "parent_id": "34567" },
+//     { "op": "delete", "id": "45678" }
+// ]
+//
+// or
+//
+// - op: create
+//   title: Runbooks
+//   parent_id: "12345"
+// - op: delete
+//   id: "45678"
+//
+// or
+//
+// op,id,title,parent_id,label
+// create,,Runbooks,12345,
+// add_label,23456,,,archive
+// delete,45678,,,
+//
+// CSV plans have a column for each field, left empty for the operations that
+// don't use it. The columns can come in any order and unused ones can be left
+// out.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Create {
+        title: String,
+        parent_id: Option<String>,
+        #[serde(default)]
+        body: String,
+    },
+    Rename {
+        id: String,
+        title: String,
+    },
+    AddLabel {
+        id: String,
+        label: String,
+    },
+    Move {
+        id: String,
+        parent_id: String,
+    },
+    Delete {
+        id: String,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operation::Create {
+                title,
+                parent_id: Some(parent_id),
+                ..
+            } => write!(f, "create page \"{}\" under {}", title, parent_id),
+            Operation::Create { title, .. } => write!(f, "create page \"{}\"", title),
+            Operation::Rename { id, title } => write!(f, "rename page {} to \"{}\"", id, title),
+            Operation::AddLabel { id, label } => {
+                write!(f, "add label \"{}\" to page {}", label, id)
+            }
+            Operation::Move { id, parent_id } => write!(f, "move page {} under {}", id, parent_id),
+            Operation::Delete { id } => write!(f, "delete page {}", id),
+        }
+    }
+}
+
+// A row of a CSV plan, which can't be tagged by op the way YAML and JSON are
+#[derive(Deserialize)]
+struct CsvRow {
+    op: String,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    parent_id: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    body: Option<String>,
+}
+
+impl CsvRow {
+    fn into_operation(self) -> Result<Operation> {
+        let op = self.op.trim();
+        let required = |value: Option<String>, column: &str| {
+            value.with_context(|| format!("{} needs a value in the {} column", op, column))
+        };
+        let operation = match op {
+            "create" => Operation::Create {
+                title: required(self.title, "title")?,
+                parent_id: self.parent_id,
+                body: self.body.unwrap_or_default(),
+            },
+            "rename" => Operation::Rename {
+                id: required(self.id, "id")?,
+                title: required(self.title, "title")?,
+            },
+            "add_label" => Operation::AddLabel {
+                id: required(self.id, "id")?,
+                label: required(self.label, "label")?,
+            },
+            "move" => Operation::Move {
+                id: required(self.id, "id")?,
+                parent_id: required(self.parent_id, "parent_id")?,
+            },
+            "delete" => Operation::Delete {
+                id: required(self.id, "id")?,
+            },
+            _ => anyhow::bail!(
+                "Unknown op \"{}\", expected create, rename, add_label, move or delete",
+                op
+            ),
+        };
+        Ok(operation)
+    }
+}
+
+fn read_csv_plan(contents: &str) -> Result<Vec<Operation>> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::Headers)
+        .from_reader(contents.as_bytes())
+        .deserialize::<CsvRow>()
+        .enumerate()
+        .map(|(i, row)| {
+            row.map_err(anyhow::Error::from)
+                .and_then(CsvRow::into_operation)
+                .with_context(|| format!("Row {} of the plan is invalid", i + 1))
+        })
+        .collect()
+}
+
+pub fn read_plan(file_name: &Path) -> Result<Vec<Operation>> {
+    let mut contents = String::new();
+    let mut file = File::open(file_name).context("Plan file could not be found")?;
+    file.read_to_string(&mut contents)
+        .context("Plan file is not readable")?;
+    let extension = file_name
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let operations = match extension {
+        "yaml" | "yml" => serde_yaml::from_str::<Vec<Operation>>(&contents).map_err(Into::into),
+        "csv" => read_csv_plan(&contents),
+        _ => serde_json::from_str::<Vec<Operation>>(&contents).map_err(Into::into),
+    };
+    operations.context("The plan file could not be parsed: check the formatting")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_plans_leave_unused_columns_empty() {
+        let plan = "op, id, title, parent_id, label\n\
+                    create,,Runbooks,12345,\n\
+                    add_label,23456,,,archive\n\
+                    delete,45678,,,\n";
+        let operations = read_csv_plan(plan).unwrap();
+        assert!(matches!(
+            &operations[0],
+            Operation::Create { title, parent_id: Some(parent_id), body }
+                if title == "Runbooks" && parent_id == "12345" && body.is_empty()
+        ));
+        assert!(matches!(
+            &operations[1],
+            Operation::AddLabel { id, label } if id == "23456" && label == "archive"
+        ));
+        assert!(matches!(&operations[2], Operation::Delete { id } if id == "45678"));
+    }
+
+    #[test]
+    fn csv_plans_need_the_columns_their_ops_use() {
+        assert!(read_csv_plan("op,id\nrename,23456\n").is_err());
+        assert!(read_csv_plan("op,title\ncreate,\n").is_err());
+        assert!(read_csv_plan("op,id\narchive,23456\n").is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+
+use concmd::conf_api::{ApiError, Page, PageSummary, Space};
+use concmd::{convert, frontmatter, Config};
+
+use crate::actions;
+
+// JSON-RPC 2.0 over stdin/stdout for editor plugins, one message per line.
+// Methods:
+//   spaces/list                          -> [{id, key, name, homepageId}]
+//   pages/list {space}                   -> [{id, title, version}]
+//   page/get   {id}                      -> {id, title, spaceId, version, markdown}
+//   page/put   {id, markdown, message?, expectedVersion?}
+//                                        -> {id, version}
+// page/put accepts markdown with or without the frontmatter concmd writes, and
+// applies title, label and parent changes in it as upload does. If the page
+// is no longer at expectedVersion, or the frontmatter's version when that
+// isn't given, nothing is published and a CONFLICT error is returned.
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Start of the range JSON-RPC leaves for application errors
+const REQUEST_FAILED: i64 = -32000;
+// The page has been changed since the version the client expected
+const CONFLICT: i64 = -32001;
+
+#[derive(Deserialize, Debug)]
+struct Request {
+    jsonrpc: String,
+    // Notifications have no id and get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpaceParams {
+    space: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct GetParams {
+    id: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PutParams {
+    id: String,
+    markdown: String,
+    message: Option<String>,
+    #[serde(rename = "expectedVersion")]
+    expected_version: Option<usize>,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+// Handles requests until stdin is closed
+pub fn run(config: &Config, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    for line in input.lines() {
+        let line = line.context("Could not read from stdin")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(message) => handle_message(config, message),
+            Err(e) => Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+        };
+        if let Some(response) = response {
+            writeln!(output, "{}", response)?;
+            output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn handle_message(config: &Config, message: Value) -> Option<Value> {
+    let request = match serde_json::from_value::<Request>(message) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(request) => {
+            return Some(error_response(
+                request.id.unwrap_or(Value::Null),
+                RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported"),
+            ))
+        }
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, e),
+            ))
+        }
+    };
+    let result = dispatch(config, &request.method, request.params);
+    let id = request.id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => error_response(id, e),
+    })
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": error.code, "message": error.message},
+    })
+}
+
+fn dispatch(config: &Config, method: &str, params: Value) -> Result<Value, RpcError> {
+    let result = match method {
+        "spaces/list" => list_spaces(config),
+        "pages/list" => list_pages(config, parse_params(params)?),
+        "page/get" => get_page(config, parse_params(params)?),
+        "page/put" => put_page(config, parse_params(params)?),
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {}", method),
+            ))
+        }
+    };
+    result.map_err(|e| {
+        let code = match e.downcast_ref::<ApiError>() {
+            Some(ApiError::Conflict { .. }) => CONFLICT,
+            _ => REQUEST_FAILED,
+        };
+        RpcError::new(code, format!("{:#}", e))
+    })
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn list_spaces(config: &Config) -> Result<Value> {
+    let spaces = Space::get_spaces(&config.api)?;
+    Ok(spaces
+        .iter()
+        .map(|space| {
+            json!({
+                "id": space.id,
+                "key": space.key,
+                "name": space.name,
+                "homepageId": space.homepage_id,
+            })
+        })
+        .collect())
+}
+
+fn list_pages(config: &Config, params: SpaceParams) -> Result<Value> {
+    let space = Space::get_space_by_key_or_id(&config.api, &params.space)?;
+    let pages = PageSummary::get_pages(&config.api, Some(&space.id), false)?;
+    Ok(pages
+        .iter()
+        .map(|page| {
+            json!({
+                "id": page.id,
+                "title": page.title,
+                "version": page.version.as_ref().map(|version| version.number),
+            })
+        })
+        .collect())
+}
+
+fn get_page(config: &Config, params: GetParams) -> Result<Value> {
+    let page = Page::get_page_by_id(&config.api, &params.id)?;
+    Ok(json!({
+        "id": page.id,
+        "title": page.title,
+        "spaceId": page.space_id,
+        "version": page.version.number,
+        "markdown": convert::body_to_markdown(page.get_body(), page.body_format(), Some(config))?,
+    }))
+}
+
+fn put_page(config: &Config, params: PutParams) -> Result<Value> {
+    let mut page = Page::get_page_by_id(&config.api, &params.id)?;
+    let (frontmatter, body) = frontmatter::split(&params.markdown)?;
+    let frontmatter = frontmatter.unwrap_or_default();
+    // There is no one to ask, so a page changed since is never published over
+    let expected_version = params.expected_version.or(frontmatter.version);
+    if let Some(expected) = expected_version.filter(|expected| *expected != page.version.number) {
+        return Err(ApiError::Conflict {
+            resource: format!("page {}", page.id),
+            detail: format!(
+                ": expected version {}, it is now version {}",
+                expected, page.version.number
+            ),
+        }
+        .into());
+    }
+    page.version.message = params.message;
+    actions::update_uploaded_page(config, &mut page, None, &frontmatter, body)?;
+    Ok(json!({"id": page.id, "version": page.version.number}))
+}
@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::SpaceSort;
+
+// How many recently used spaces are remembered for sorting by
+const RECENT_SPACES: usize = 20;
+
+// Local state that has to outlive a single run of concmd, stored as json
+// alongside the config file.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    #[serde(default)]
+    pub pending_edits: Vec<PendingEdit>,
+    // The last commit git-sync published, by repository root
+    #[serde(default)]
+    pub git_syncs: HashMap<PathBuf, String>,
+    // Ids of the spaces last chosen or given to new and upload, newest first
+    #[serde(default)]
+    pub recent_spaces: Vec<String>,
+    // The order spaces were last listed in with spaces --sort
+    #[serde(default)]
+    pub space_sort: SpaceSort,
+}
+
+// An edit that has been saved to disk but not yet published or discarded.
+// Anything left here at startup means concmd crashed, was killed, or failed to
+// upload after the editor closed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingEdit {
+    pub id: String,
+    pub title: String,
+    pub file_path: PathBuf,
+}
+
+impl State {
+    fn state_path() -> PathBuf {
+        let mut path = home::home_dir().expect("home dir should always exist");
+        path.push(".config/concmd/state.json");
+        path
+    }
+
+    pub fn load() -> Result<State> {
+        match fs::read_to_string(State::state_path()) {
+            Ok(contents) => serde_json::from_str::<State>(&contents)
+                .context("The state file could not be parsed"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(e) => Err(e).context("The state file is not readable"),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = State::state_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .context("The state file could not be written")
+    }
+}
+
+pub fn add_pending_edit(edit: PendingEdit) -> Result<()> {
+    let mut state = State::load()?;
+    state.pending_edits.retain(|pending| pending.id != edit.id);
+    state.pending_edits.push(edit);
+    state.save()
+}
+
+pub fn remove_pending_edit(id: &str) -> Result<()> {
+    let mut state = State::load()?;
+    state.pending_edits.retain(|pending| pending.id != id);
+    state.save()
+}
+
+pub fn synced_commit(repo: &Path) -> Result<Option<String>> {
+    Ok(State::load()?.git_syncs.get(repo).cloned())
+}
+
+pub fn set_synced_commit(repo: &Path, commit: &str) -> Result<()> {
+    let mut state = State::load()?;
+    state
+        .git_syncs
+        .insert(repo.to_path_buf(), commit.to_string());
+    state.save()
+}
+
+pub fn add_recent_space(id: &str) -> Result<()> {
+    let mut state = State::load()?;
+    state.recent_spaces.retain(|recent| recent != id);
+    state.recent_spaces.insert(0, id.to_string());
+    state.recent_spaces.truncate(RECENT_SPACES);
+    state.save()
+}
+
+pub fn set_space_sort(sort: SpaceSort) -> Result<()> {
+    let mut state = State::load()?;
+    state.space_sort = sort;
+    state.save()
+}